@@ -0,0 +1,658 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use os_pipe::PipeReader;
+use serde::{Deserialize, Deserializer};
+use songbird::input::{AudioStream, HttpRequest, Input, LiveInput, ReadOnlySource};
+use songbird::input::core::io::MediaSource;
+use songbird::input::core::probe::Hint;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::cache::MediaCache;
+use crate::pot::PlaylistItem;
+use crate::radio;
+use crate::ytdlp::{self, CancelToken, MediaStream};
+
+pub const YT_DLP: &str = "yt-dlp";
+pub const YOUTUBE_DL: &str = "youtube-dl";
+pub const HTTP: &str = "http";
+pub const LOCAL: &str = "local";
+
+/// Urls ending with these extensions are played straight from the server
+pub const AUDIO_EXTENSIONS: [&str; 8] = ["mp3", "ogg", "opus", "flac", "wav", "m4a", "aac", "webm"];
+
+/// Audio of an item, radios also send the title of the song on air when it changes
+pub struct Media {
+    pub input: Input,
+    pub titles: Option<UnboundedReceiver<String>>,
+    /// Where the track has to be seeked once it plays, streams that can't be seeked already start there
+    pub seek_to: Option<Duration>
+}
+
+impl Media {
+    /// Seek to the start time of the item, it's only for inputs that can be seeked
+    fn starting_at(mut self, start_time: Option<f32>) -> Self {
+        self.seek_to = start_time.filter(|start_time| *start_time > 0.0).map(Duration::from_secs_f32);
+        self
+    }
+}
+
+impl From<Input> for Media {
+    fn from(input: Input) -> Self {
+        Self { input, titles: None, seek_to: None }
+    }
+}
+
+/// Audio read from a pipe, it can't be seeked
+fn pipe_input(reader: PipeReader, hint: Option<Hint>) -> Input {
+    Input::Live(
+        LiveInput::Raw(AudioStream {
+            input: Box::new(ReadOnlySource::new(reader)) as Box<dyn MediaSource>,
+            hint,
+        }),
+        None
+    )
+}
+
+/// Somewhere songs come from, it turns urls into playlist items and playlist items into audio
+#[async_trait]
+pub trait MediaBackend: Send + Sync {
+    /// Saved in the playlist items so they are played by the backend that resolved them
+    fn name(&self) -> &str;
+
+    /// Items of the input, a playlist gives an item per song
+    async fn resolve(&self, input: &str, cancel: &mut CancelToken) -> anyhow::Result<Vec<PlaylistItem>>;
+
+    /// Audio of an item returned by `resolve`
+    async fn fetch(&self, item: &PlaylistItem, cancel: &mut CancelToken) -> anyhow::Result<Media>;
+}
+
+/// yt-dlp or anything that takes the same arguments, like youtube-dl or a fake script
+pub struct YtDlpBackend {
+    name: String,
+    binary: String
+}
+
+impl YtDlpBackend {
+    pub fn new(name: &str, binary: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            binary: binary.to_owned()
+        }
+    }
+}
+
+#[async_trait]
+impl MediaBackend for YtDlpBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn resolve(&self, input: &str, cancel: &mut CancelToken) -> anyhow::Result<Vec<PlaylistItem>> {
+        let ytdl_args = [
+            "-j",
+            "-f",
+            "webm[abr>0]/bestaudio/best",
+            "-R",
+            "infinite",
+            "--yes-playlist",
+            "--ignore-config",
+            "--no-warnings",
+            input,
+            "-o",
+            "-",
+        ];
+
+        let jsons = ytdlp::json_lines(&self.binary, &ytdl_args, cancel).await?;
+
+        let items: Vec<PlaylistItem> = jsons.iter().filter_map(|json_str| {
+            match serde_json::from_str::<PlaylistItem>(json_str) {
+                Ok(mut item) => {
+                    item.backend = Some(self.name.to_owned());
+                    Some(item)
+                },
+                Err(_) => None,
+            }
+        }).collect();
+
+        Ok(items)
+    }
+
+    /// From the cache when it's there or streamed from yt-dlp while it's saved to the cache,
+    /// items with a start time are streamed from there and not cached
+    async fn fetch(&self, item: &PlaylistItem, cancel: &mut CancelToken) -> anyhow::Result<Media> {
+        let (extractor, id) = (item.extractor.to_owned(), item.id.to_owned());
+        let cached_path = tokio::task::spawn_blocking(move || MediaCache::global().lookup(&extractor, &id)).await?;
+
+        if let Some(cached_path) = cached_path {
+            tracing::debug!("playing {}/{} from the cache", &item.extractor, &item.id);
+            let input: Input = songbird::input::File::new(cached_path.to_string_lossy().into_owned()).into();
+            return Ok(Media::from(input).starting_at(item.start_time))
+        }
+
+        tracing::debug!("streaming {} from {}", &item.original_url, &self.name);
+        let path = MediaCache::global().media_path(&item.extractor, &item.id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut ytdl_args = vec![
+            "-f",
+            "webm[abr>0]/bestaudio/best",
+            "-R",
+            "infinite",
+            "--no-playlist",
+            "--ignore-config",
+            "--no-warnings",
+            item.original_url.as_str(),
+            "-o",
+            "-",
+        ];
+
+        // The pipe can't be seeked so yt-dlp cuts the media at the start time
+        let section = item.start_time.filter(|start_time| *start_time > 0.0).map(|start_time| format!("*{}-inf", start_time));
+        if let Some(section) = &section {
+            ytdl_args.extend(["--download-sections", section.as_str()]);
+        }
+
+        let mut stream = MediaStream::spawn(&self.binary, &ytdl_args)?;
+
+        // yt-dlp errors show up here instead of as a silent track
+        let first_chunk = stream.first_chunk(cancel).await?;
+
+        // Only the first of several plays of the same media at once writes it to the cache, cut media is never cached
+        let download = match section {
+            Some(_) => None,
+            None => CacheDownload::begin(&item.extractor, &item.id),
+        };
+        let reader = tee_to_cache(stream, first_chunk, cancel.clone(), item, download)?;
+
+        Ok(pipe_input(reader, None).into())
+    }
+}
+
+/// Media files being downloaded into the cache
+static DOWNLOADS: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// The `.part` file of a download, only one download of the same media can write to it at a time
+struct CacheDownload {
+    part_path: PathBuf
+}
+
+impl CacheDownload {
+    /// None when the media is already being downloaded
+    fn begin(extractor: &str, id: &str) -> Option<Self> {
+        let part_path = MediaCache::global().media_path(extractor, &format!("{}.part", id));
+
+        match DOWNLOADS.lock().unwrap().insert(part_path.clone()) {
+            true => Some(Self { part_path }),
+            false => None,
+        }
+    }
+}
+
+impl Drop for CacheDownload {
+    fn drop(&mut self) {
+        DOWNLOADS.lock().unwrap().remove(&self.part_path);
+    }
+}
+
+/// Copy the yt-dlp stdout to a pipe for playback and to a `.part` file at the same time,
+/// the part file goes into the cache when yt-dlp finished so the cache never has half written files.
+/// Without a download the stream is only played.
+/// yt-dlp is killed when the token is cancelled or it stops sending data
+fn tee_to_cache (mut stream: MediaStream, first_chunk: Vec<u8>, mut cancel: CancelToken, item: &PlaylistItem, download: Option<CacheDownload>) -> anyhow::Result<PipeReader> {
+    let (reader, mut writer) = os_pipe::pipe()?;
+
+    let (extractor, id, source_url) = (item.extractor.to_owned(), item.id.to_owned(), item.original_url.to_owned());
+    let mut part = match &download {
+        Some(download) => Some(fs::File::create(&download.part_path)?),
+        None => None,
+    };
+
+    // Writing to the pipe blocks while the track is paused so the copy gets its own thread
+    let runtime = tokio::runtime::Handle::current();
+    std::thread::spawn(move || runtime.block_on(async move {
+        let mut chunk = first_chunk;
+        let mut playing = true;
+
+        let complete = loop {
+            if let Some(file) = &mut part {
+                if file.write_all(&chunk).is_err() {
+                    break false
+                }
+            }
+            // Playback stopped, keep downloading for the cache
+            if playing && writer.write_all(&chunk).is_err() {
+                if part.is_none() {
+                    break false
+                }
+                playing = false;
+            }
+
+            chunk.resize(ytdlp::CHUNK_SIZE, 0);
+            let read = tokio::select! {
+                read = tokio::time::timeout(ytdlp::STALL_TIMEOUT, stream.stdout.read(&mut chunk)) => read,
+                _ = cancel.cancelled() => break false,
+            };
+
+            match read {
+                Ok(Ok(0)) => break true,
+                Ok(Ok(read)) => chunk.truncate(read),
+                Ok(Err(err)) if err.kind() == ErrorKind::Interrupted => chunk.clear(),
+                // Read error or yt-dlp stalled
+                _ => break false,
+            }
+        };
+
+        // Closing the pipe ends the track
+        drop(writer);
+
+        let success = complete && stream.child.wait().await.map(|status| status.success()).unwrap_or(false);
+        if !success {
+            let _ = stream.child.kill().await;
+        }
+
+        let (download, part) = match (download, part) {
+            (Some(download), Some(part)) => (download, part),
+            _ => return,
+        };
+
+        let result = if success {
+            part.sync_all()
+                .map_err(anyhow::Error::from)
+                .and_then(|_| MediaCache::global().insert(&extractor, &id, &download.part_path, &source_url).map(|_| ()))
+        } else {
+            fs::remove_file(&download.part_path).map_err(anyhow::Error::from)
+        };
+
+        if let Err(err) = result {
+            tracing::warn!("cannot save {}/{} to the cache: {:?}", &extractor, &id, err);
+        }
+    }));
+
+    Ok(reader)
+}
+
+/// Audio files and Icecast or Shoutcast radios served over http, decoded by symphonia and never cached
+#[derive(Default)]
+pub struct HttpBackend {
+    client: reqwest::Client
+}
+
+#[async_trait]
+impl MediaBackend for HttpBackend {
+    fn name(&self) -> &str {
+        HTTP
+    }
+
+    async fn resolve(&self, input: &str, _cancel: &mut CancelToken) -> anyhow::Result<Vec<PlaylistItem>> {
+        let url = url::Url::parse(input)?;
+        let probe = radio::probe(input).await?;
+
+        if !probe.is_audio() {
+            return Err(anyhow!("{} is not an audio file or a radio stream", input))
+        }
+
+        let file_name = url
+            .path_segments()
+            .and_then(|segments| segments.last())
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| segment.to_owned());
+
+        let title = probe.name.clone()
+            .or(file_name)
+            .or_else(|| url.host_str().map(|host| host.to_owned()))
+            .unwrap_or_else(|| input.to_owned());
+
+        let mut item = PlaylistItem::new(input, &title, input, HTTP, HTTP);
+        item.is_live = Some(probe.live);
+
+        Ok(vec![item])
+    }
+
+    async fn fetch(&self, item: &PlaylistItem, cancel: &mut CancelToken) -> anyhow::Result<Media> {
+        if item.is_live != Some(true) {
+            let input: Input = HttpRequest::new(self.client.clone(), item.original_url.to_owned()).into();
+            return Ok(Media::from(input).starting_at(item.start_time))
+        }
+
+        let (reader, content_type, titles) = radio::open(&item.original_url, cancel.clone()).await?;
+
+        // Radios don't send a file header, the content type tells symphonia what to expect
+        let hint = content_type.map(|content_type| {
+            let mut hint = Hint::new();
+            hint.mime_type(&content_type);
+            hint
+        });
+
+        Ok(Media {
+            input: pipe_input(reader, hint),
+            titles: Some(titles),
+            seek_to: None
+        })
+    }
+}
+
+/// Files in a directory of the bot host, nothing outside of it can be played
+pub struct LocalBackend {
+    root: PathBuf
+}
+
+impl LocalBackend {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into()
+        }
+    }
+
+    /// Path of a `file://` url or a path relative to the root, it must be a file inside the root
+    fn path(&self, input: &str) -> anyhow::Result<(PathBuf, String)> {
+        let relative = match url::Url::parse(input) {
+            Ok(url) if url.scheme() == "file" => url.to_file_path().map_err(|_| anyhow!("Invalid file url"))?,
+            _ => PathBuf::from(input),
+        };
+        let relative = relative.strip_prefix("/").unwrap_or(&relative).to_path_buf();
+
+        let root = fs::canonicalize(&self.root).map_err(|_| anyhow!("The local media directory doesn't exist"))?;
+        let path = fs::canonicalize(root.join(&relative)).map_err(|_| anyhow!("File not found"))?;
+
+        if !path.starts_with(&root) || !path.is_file() {
+            return Err(anyhow!("File not found"))
+        }
+
+        let relative = path.strip_prefix(&root)?.to_string_lossy().into_owned();
+
+        Ok((path, relative))
+    }
+}
+
+#[async_trait]
+impl MediaBackend for LocalBackend {
+    fn name(&self) -> &str {
+        LOCAL
+    }
+
+    async fn resolve(&self, input: &str, _cancel: &mut CancelToken) -> anyhow::Result<Vec<PlaylistItem>> {
+        let (path, relative) = self.path(input)?;
+
+        let title = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_else(|| relative.to_owned());
+
+        Ok(vec![PlaylistItem::new(&relative, &title, &format!("file:///{}", &relative), LOCAL, LOCAL)])
+    }
+
+    async fn fetch(&self, item: &PlaylistItem, _cancel: &mut CancelToken) -> anyhow::Result<Media> {
+        let (path, _) = self.path(&item.id)?;
+
+        let input: Input = songbird::input::File::new(path.to_string_lossy().into_owned()).into();
+        Ok(Media::from(input).starting_at(item.start_time))
+    }
+}
+
+/// The backends by name and the rules that choose the backend of a url
+pub struct BackendRegistry {
+    backends: HashMap<String, Arc<dyn MediaBackend>>,
+    /// Hosts and the backend for their urls, the first match wins
+    routes: Vec<(String, String)>,
+    /// Used for the urls that match no rule and for searches
+    default: String
+}
+
+impl BackendRegistry {
+    pub fn new(default: Arc<dyn MediaBackend>) -> Self {
+        let mut registry = Self {
+            backends: HashMap::new(),
+            routes: Vec::new(),
+            default: default.name().to_owned()
+        };
+
+        registry.register(default);
+        registry
+    }
+
+    /// yt-dlp, youtube-dl, http and local files, the binaries come from `YTDLP_PATH` and `YOUTUBE_DL_PATH`,
+    /// the local files from `LOCAL_MEDIA_PATH` and the routes from `BACKEND_ROUTES` as `host=backend,host=backend`
+    pub fn from_env() -> Self {
+        let env = |key: &str, default: &str| std::env::var(key).ok().filter(|value| !value.is_empty()).unwrap_or_else(|| default.to_owned());
+
+        let mut registry = Self::new(Arc::new(YtDlpBackend::new(YT_DLP, &env("YTDLP_PATH", YT_DLP))));
+        registry.register(Arc::new(YtDlpBackend::new(YOUTUBE_DL, &env("YOUTUBE_DL_PATH", YOUTUBE_DL))));
+        registry.register(Arc::new(HttpBackend::default()));
+        registry.register(Arc::new(LocalBackend::new(env("LOCAL_MEDIA_PATH", "data/media"))));
+
+        for route in env("BACKEND_ROUTES", "").split(',').filter(|route| !route.trim().is_empty()) {
+            match route.split_once('=') {
+                Some((host, backend)) if registry.backends.contains_key(backend.trim()) => registry.add_route(host.trim(), backend.trim()),
+                _ => tracing::warn!("ignoring invalid backend route {}", route),
+            }
+        }
+
+        registry
+    }
+
+    /// Add a backend, it replaces the one with the same name
+    pub fn register(&mut self, backend: Arc<dyn MediaBackend>) {
+        self.backends.insert(backend.name().to_owned(), backend);
+    }
+
+    /// Urls of the host and its subdomains go to the backend
+    pub fn add_route(&mut self, host: &str, backend: &str) {
+        self.routes.push((host.trim_start_matches("www.").to_lowercase(), backend.to_owned()));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn MediaBackend>> {
+        self.backends.get(name).cloned()
+    }
+
+    pub fn default_backend(&self) -> Arc<dyn MediaBackend> {
+        self.backends[&self.default].clone()
+    }
+
+    /// Backend for a url, file urls are local files, then the host routes are checked,
+    /// then urls of audio files are played over http and everything else goes to the default backend
+    pub fn route(&self, url: &url::Url) -> Arc<dyn MediaBackend> {
+        self.rule(url).unwrap_or_else(|| self.default_backend())
+    }
+
+    /// Like `route` but the server of an url that matches no rule is asked what it is,
+    /// radio streams and audio files without extension go to the http backend
+    pub async fn route_probed(&self, url: &url::Url) -> Arc<dyn MediaBackend> {
+        if let Some(backend) = self.rule(url) {
+            return backend
+        }
+
+        if let (Some(http), "http" | "https") = (self.get(HTTP), url.scheme()) {
+            if radio::probe(url.as_str()).await.map(|probe| probe.is_audio()).unwrap_or(false) {
+                return http
+            }
+        }
+
+        self.default_backend()
+    }
+
+    fn rule(&self, url: &url::Url) -> Option<Arc<dyn MediaBackend>> {
+        if url.scheme() == "file" {
+            if let Some(local) = self.get(LOCAL) {
+                return Some(local)
+            }
+        }
+
+        let host = url.host_str().unwrap_or("").trim_start_matches("www.").to_lowercase();
+        let routed = self.routes.iter().find(|(route_host, _)| host == *route_host || host.ends_with(&format!(".{}", route_host)));
+
+        if let Some(backend) = routed.and_then(|(_, backend)| self.get(backend)) {
+            return Some(backend)
+        }
+
+        let extension = url.path().rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+        if extension.map(|extension| AUDIO_EXTENSIONS.contains(&extension.as_str())).unwrap_or(false) {
+            return self.get(HTTP)
+        }
+
+        None
+    }
+
+    /// The backend that resolved the item, or the one its url routes to when it's not registered anymore
+    pub fn for_item(&self, item: &PlaylistItem) -> Arc<dyn MediaBackend> {
+        if let Some(backend) = item.backend.as_deref().and_then(|name| self.get(name)) {
+            return backend
+        }
+
+        match url::Url::parse(&item.original_url) {
+            Ok(url) => self.route(&url),
+            Err(_) => self.default_backend(),
+        }
+    }
+}
+
+impl fmt::Debug for BackendRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackendRegistry")
+            .field("backends", &self.backends.keys().collect::<Vec<_>>())
+            .field("routes", &self.routes)
+            .field("default", &self.default)
+            .finish()
+    }
+}
+
+/// Backend name of a saved item, items saved before the backends were pluggable have `YT_DLP` or `YOUTUBE_DL`
+pub fn deserialize_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let name = Option::<String>::deserialize(deserializer)?;
+
+    Ok(name.map(|name| match name.as_str() {
+        "YT_DLP" => YT_DLP.to_owned(),
+        "YOUTUBE_DL" => YOUTUBE_DL.to_owned(),
+        _ => name,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::Arc;
+
+    use super::{BackendRegistry, CacheDownload, LocalBackend, MediaBackend, YtDlpBackend};
+    use crate::pot::PlaylistItem;
+    use crate::test_utils::{fake_backend, temp_dir};
+    use crate::ytdlp::{CancelToken, MediaError};
+
+    fn registry() -> BackendRegistry {
+        let mut registry = BackendRegistry::new(Arc::new(YtDlpBackend::new("yt-dlp", "yt-dlp")));
+        registry.register(Arc::new(YtDlpBackend::new("youtube-dl", "youtube-dl")));
+        registry.register(Arc::new(super::HttpBackend::default()));
+        registry.register(Arc::new(LocalBackend::new("data/media")));
+        registry.add_route("soundcloud.com", "youtube-dl");
+        registry
+    }
+
+    fn route(registry: &BackendRegistry, url: &str) -> String {
+        registry.route(&url::Url::parse(url).unwrap()).name().to_owned()
+    }
+
+    #[tokio::test]
+    async fn resolve_parses_items() {
+        let script = fake_backend("resolve", r#"
+echo '{"id":"a","title":"First","original_url":"https://example.com/a","extractor":"fake"}' >&2
+echo '{"id":"b","title":"Second","original_url":"https://example.com/b","extractor":"fake"}' >&2
+"#);
+        let backend = YtDlpBackend::new("fake", &script);
+
+        let items = backend.resolve("https://example.com/list", &mut CancelToken::never()).await.unwrap();
+
+        let titles: Vec<&str> = items.iter().map(|item| item.title.as_str()).collect();
+        assert_eq!(titles, vec!["First", "Second"]);
+        assert_eq!(items[0].backend.as_deref(), Some("fake"));
+    }
+
+    #[tokio::test]
+    async fn resolve_reports_geo_block() {
+        let script = fake_backend("geo", r#"
+echo "ERROR: [youtube] abc: The uploader has not made this video available in your country" >&2
+exit 1
+"#);
+        let backend = YtDlpBackend::new("fake", &script);
+
+        let error = backend.resolve("https://example.com/abc", &mut CancelToken::never()).await.unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<MediaError>(), Some(MediaError::GeoBlocked)));
+    }
+
+    #[tokio::test]
+    async fn resolve_reports_extractor_error() {
+        let script = fake_backend("extractor", r#"
+echo "ERROR: Unsupported URL: https://example.com/abc" >&2
+exit 1
+"#);
+        let backend = YtDlpBackend::new("fake", &script);
+
+        let error = backend.resolve("https://example.com/abc", &mut CancelToken::never()).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "Unsupported URL: https://example.com/abc");
+    }
+
+    #[tokio::test]
+    async fn missing_binary() {
+        let backend = YtDlpBackend::new("fake", "/nonexistent/yt-dlp");
+
+        let error = backend.resolve("https://example.com/abc", &mut CancelToken::never()).await.unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<MediaError>(), Some(MediaError::BinaryMissing(_))));
+    }
+
+    #[test]
+    fn one_cache_download_at_a_time() {
+        let first = CacheDownload::begin("fake", "one-at-a-time").unwrap();
+        assert!(CacheDownload::begin("fake", "one-at-a-time").is_none());
+        assert!(CacheDownload::begin("fake", "another").is_some());
+
+        drop(first);
+        assert!(CacheDownload::begin("fake", "one-at-a-time").is_some());
+    }
+
+    #[test]
+    fn routes() {
+        let registry = registry();
+
+        assert_eq!(route(&registry, "https://soundcloud.com/artist/song"), "youtube-dl");
+        assert_eq!(route(&registry, "https://m.soundcloud.com/artist/song"), "youtube-dl");
+        assert_eq!(route(&registry, "https://example.com/music/song.MP3"), "http");
+        assert_eq!(route(&registry, "file:///song.mp3"), "local");
+        assert_eq!(route(&registry, "https://www.youtube.com/watch?v=abc"), "yt-dlp");
+    }
+
+    #[test]
+    fn old_backend_names() {
+        let item = serde_json::from_str::<PlaylistItem>(
+            r#"{"id":"a","title":"A","original_url":"https://example.com/a","extractor":"youtube","thumbnail":null,"duration":null,"playlist_id":null,"webpage_url":null,"is_live":null,"was_live":null,"backend":"YOUTUBE_DL"}"#
+        ).unwrap();
+
+        assert_eq!(item.backend.as_deref(), Some("youtube-dl"));
+        assert_eq!(registry().for_item(&item).name(), "youtube-dl");
+    }
+
+    #[tokio::test]
+    async fn local_files_stay_in_the_root() {
+        let dir = temp_dir("local");
+        fs::create_dir_all(dir.join("root/album")).unwrap();
+        fs::write(dir.join("root/album/song.ogg"), b"OggS").unwrap();
+        fs::write(dir.join("secret.txt"), b"secret").unwrap();
+
+        let backend = LocalBackend::new(dir.join("root"));
+
+        let items = backend.resolve("file:///album/song.ogg", &mut CancelToken::never()).await.unwrap();
+        assert_eq!(items[0].id, "album/song.ogg");
+        assert_eq!(items[0].title, "song");
+
+        assert!(backend.resolve("../secret.txt", &mut CancelToken::never()).await.is_err());
+        assert!(backend.resolve("album/missing.ogg", &mut CancelToken::never()).await.is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::helpers;
+
+const CACHE_PATH: &str = "data/cache";
+
+static CACHE: Lazy<MediaCache> = Lazy::new(|| MediaCache::new(CACHE_PATH, max_size()));
+
+/// Metadata of a cached media file, saved in `meta/<extractor>/<id>.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub extractor: String,
+    pub id: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Unix timestamp in seconds
+    pub last_played: u64,
+    pub source_url: String,
+    /// Container detected from the file header
+    pub format: Option<String>,
+    /// FNV-1a hash of the file contents in hex
+    pub checksum: String
+}
+
+/// Media files downloaded by yt-dlp, limited to a size and evicting the least recently played files first
+pub struct MediaCache {
+    media_dir: PathBuf,
+    meta_dir: PathBuf,
+    /// Size limit in bytes
+    max_size: u64,
+    /// Cache operations touch several files, they are done one at a time
+    lock: Mutex<()>
+}
+
+impl MediaCache {
+    /// Cache with the media in `<root>/media` and the metadata in `<root>/meta`
+    pub fn new (root: impl Into<PathBuf>, max_size: u64) -> Self {
+        let root = root.into();
+
+        Self {
+            media_dir: root.join("media"),
+            meta_dir: root.join("meta"),
+            max_size,
+            lock: Mutex::new(())
+        }
+    }
+
+    /// The cache in `data/cache` limited to `CACHE_MAX_SIZE_MB`
+    pub fn global () -> &'static Self {
+        &CACHE
+    }
+
+    pub fn media_path (&self, extractor: &str, id: &str) -> PathBuf {
+        self.media_dir.join(extractor).join(id)
+    }
+
+    fn meta_path (&self, extractor: &str, id: &str) -> PathBuf {
+        self.meta_dir.join(extractor).join(format!("{}.json", id))
+    }
+
+    /// Returns the path of the cached file if it's there, marking it as played.
+    /// Only the size is compared so hits stay cheap, the contents are verified by `insert` and `cleanup`
+    pub fn lookup (&self, extractor: &str, id: &str) -> Option<PathBuf> {
+        let _lock = self.lock.lock().unwrap();
+
+        let media_path = self.media_path(extractor, id);
+        if !media_path.is_file() { return None }
+
+        let size = fs::metadata(&media_path).map(|attributes| attributes.len()).unwrap_or(0);
+        let mut entry = match self.read_entry(extractor, id) {
+            Some(entry) if entry.size == size => entry,
+            _ => {
+                tracing::warn!("removing corrupt cache file {}", media_path.display());
+                self.remove_entry(extractor, id);
+                return None
+            },
+        };
+
+        entry.last_played = now();
+        self.write_entry(&entry);
+
+        Some(media_path)
+    }
+
+    /// Move a finished download into the cache, recording its metadata and evicting old files when the cache is too big
+    pub fn insert (&self, extractor: &str, id: &str, download_path: &Path, source_url: &str) -> anyhow::Result<PathBuf> {
+        let _lock = self.lock.lock().unwrap();
+
+        let size = fs::metadata(download_path)?.len();
+        if size == 0 {
+            let _ = fs::remove_file(download_path);
+            return Err(anyhow!("The download is empty"))
+        }
+
+        let media_path = self.media_path(extractor, id);
+        if let Some(parent) = media_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let entry = CacheEntry {
+            extractor: extractor.to_owned(),
+            id: id.to_owned(),
+            size,
+            last_played: now(),
+            source_url: source_url.to_owned(),
+            format: detect_format(download_path),
+            checksum: checksum(download_path)?
+        };
+
+        // The rename is atomic so the media file is always complete
+        fs::rename(download_path, &media_path)?;
+        self.write_entry(&entry);
+
+        self.evict(self.max_size);
+
+        Ok(media_path)
+    }
+
+    /// Remove the least recently played files until the cache fits in `max_size` bytes
+    fn evict (&self, max_size: u64) {
+        let mut entries = self.read_entries();
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+
+        entries.sort_by_key(|entry| entry.last_played);
+
+        for entry in entries {
+            if total <= max_size { break }
+
+            tracing::info!("evicting {}/{} from the cache", &entry.extractor, &entry.id);
+            self.remove_entry(&entry.extractor, &entry.id);
+            total = total.saturating_sub(entry.size);
+        }
+    }
+
+    fn remove_entry (&self, extractor: &str, id: &str) {
+        for path in [self.media_path(extractor, id), self.meta_path(extractor, id)] {
+            if let Err(err) = fs::remove_file(&path) {
+                if err.kind() != ErrorKind::NotFound {
+                    tracing::warn!("cannot remove {}: {:?}", path.display(), err);
+                }
+            }
+        }
+    }
+
+    /// Delete the leftovers of interrupted downloads, the files without metadata and the files
+    /// that don't match their checksum, then enforce the size limit. Reads every cached file, it's run at startup
+    pub fn cleanup (&self) {
+        let _lock = self.lock.lock().unwrap();
+
+        for extractor_dir in read_dirs(&self.media_dir) {
+            let extractor = file_name(&extractor_dir);
+
+            for media_path in read_files(&extractor_dir) {
+                let id = file_name(&media_path);
+
+                if !self.meta_path(&extractor, &id).is_file() {
+                    tracing::info!("removing {} from the cache, it has no metadata", media_path.display());
+                    let _ = fs::remove_file(&media_path);
+                }
+            }
+        }
+
+        for entry in self.read_entries() {
+            let media_path = self.media_path(&entry.extractor, &entry.id);
+
+            if !media_path.is_file() {
+                self.remove_entry(&entry.extractor, &entry.id);
+            } else if !verify(&media_path, &entry) {
+                tracing::warn!("removing corrupt cache file {}", media_path.display());
+                self.remove_entry(&entry.extractor, &entry.id);
+            }
+        }
+
+        self.evict(self.max_size);
+    }
+
+    fn read_entry (&self, extractor: &str, id: &str) -> Option<CacheEntry> {
+        let json = fs::read_to_string(self.meta_path(extractor, id)).ok()?;
+        serde_json::from_str::<CacheEntry>(&json).ok()
+    }
+
+    fn write_entry (&self, entry: &CacheEntry) {
+        let path = self.meta_path(&entry.extractor, &entry.id);
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+
+        let result = serde_json::to_string(entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| helpers::write_json(&path.to_string_lossy(), json).map_err(anyhow::Error::from));
+
+        if let Err(err) = result {
+            tracing::warn!("cannot write cache metadata {}: {:?}", path.display(), err);
+        }
+    }
+
+    fn read_entries (&self) -> Vec<CacheEntry> {
+        read_dirs(&self.meta_dir)
+            .iter()
+            .flat_map(|extractor_dir| read_files(extractor_dir))
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .filter_map(|json| serde_json::from_str::<CacheEntry>(&json).ok())
+            .collect()
+    }
+}
+
+/// Cache limit from `CACHE_MAX_SIZE_MB`, 2 GB by default
+fn max_size () -> u64 {
+    let megabytes = std::env::var("CACHE_MAX_SIZE_MB").ok().and_then(|value| value.parse::<u64>().ok()).unwrap_or(2048);
+    megabytes * 1024 * 1024
+}
+
+fn now () -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+fn verify (media_path: &Path, entry: &CacheEntry) -> bool {
+    let size = fs::metadata(media_path).map(|attributes| attributes.len()).unwrap_or(0);
+    size == entry.size && checksum(media_path).map(|checksum| checksum == entry.checksum).unwrap_or(false)
+}
+
+/// FNV-1a of the file, stable between builds unlike the std hasher
+fn checksum (path: &Path) -> anyhow::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut buffer = [0u8; 64 * 1024];
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 { break }
+
+        for byte in &buffer[..read] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    Ok(format!("{:016x}", hash))
+}
+
+/// Guess the container from the first bytes of the file
+fn detect_format (path: &Path) -> Option<String> {
+    let mut header = [0u8; 12];
+    let read = fs::File::open(path).and_then(|mut file| file.read(&mut header)).ok()?;
+    let header = &header[..read];
+
+    let format = if header.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        "webm"
+    } else if header.starts_with(b"OggS") {
+        "ogg"
+    } else if header.starts_with(b"fLaC") {
+        "flac"
+    } else if header.starts_with(b"RIFF") {
+        "wav"
+    } else if header.starts_with(b"ID3") || (header.len() > 1 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0) {
+        "mp3"
+    } else if header.len() >= 8 && &header[4..8] == b"ftyp" {
+        "mp4"
+    } else {
+        return None
+    };
+
+    Some(format.to_owned())
+}
+
+fn read_dirs (path: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).filter(|path| path.is_dir()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn read_files (path: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).filter(|path| path.is_file()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn file_name (path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::MediaCache;
+    use crate::test_utils::temp_dir;
+
+    /// Downloaded file ready to be inserted
+    fn download(cache: &MediaCache, id: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = cache.media_path("fake", &format!("{}.part", id));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn insert_and_lookup() {
+        let cache = MediaCache::new(temp_dir("cache-insert"), 1024);
+
+        assert!(cache.lookup("fake", "a").is_none());
+
+        let part = download(&cache, "a", b"OggS audio");
+        let path = cache.insert("fake", "a", &part, "https://example.com/a").unwrap();
+        assert!(!part.exists());
+
+        assert_eq!(cache.lookup("fake", "a"), Some(path.clone()));
+        let entry = cache.read_entry("fake", "a").unwrap();
+        assert_eq!(entry.size, 10);
+        assert_eq!(entry.format.as_deref(), Some("ogg"));
+        assert_eq!(entry.source_url, "https://example.com/a");
+
+        // Empty downloads never get in
+        let part = download(&cache, "b", b"");
+        assert!(cache.insert("fake", "b", &part, "https://example.com/b").is_err());
+        assert!(cache.lookup("fake", "b").is_none());
+    }
+
+    #[test]
+    fn corrupt_files_are_removed() {
+        let cache = MediaCache::new(temp_dir("cache-corrupt"), 1024);
+
+        let part = download(&cache, "a", b"OggS audio");
+        let path = cache.insert("fake", "a", &part, "https://example.com/a").unwrap();
+        let part = download(&cache, "b", b"OggS audio");
+        let other = cache.insert("fake", "b", &part, "https://example.com/b").unwrap();
+
+        // Same size, different contents
+        fs::write(&path, b"OggS AUDIO").unwrap();
+        cache.cleanup();
+        assert!(!path.exists());
+        assert!(cache.read_entry("fake", "a").is_none());
+        assert!(other.exists());
+
+        // A different size is caught without reading the file
+        fs::write(&other, b"OggS").unwrap();
+        assert!(cache.lookup("fake", "b").is_none());
+        assert!(!other.exists());
+    }
+
+    #[test]
+    fn least_recently_played_are_evicted_first() {
+        let cache = MediaCache::new(temp_dir("cache-evict"), 1024);
+
+        for id in ["a", "b", "c"] {
+            let part = download(&cache, id, &[0u8; 100]);
+            cache.insert("fake", id, &part, "https://example.com").unwrap();
+        }
+        for (id, last_played) in [("a", 30), ("b", 10), ("c", 20)] {
+            let mut entry = cache.read_entry("fake", id).unwrap();
+            entry.last_played = last_played;
+            cache.write_entry(&entry);
+        }
+
+        let cached = |cache: &MediaCache| -> Vec<bool> {
+            ["a", "b", "c"].iter().map(|id| cache.media_path("fake", id).exists()).collect()
+        };
+
+        cache.evict(200);
+        assert_eq!(cached(&cache), vec![true, false, true]);
+
+        cache.evict(100);
+        assert_eq!(cached(&cache), vec![true, false, false]);
+        assert!(cache.read_entry("fake", "c").is_none());
+    }
+
+    #[test]
+    fn cleanup_removes_leftovers() {
+        let cache = MediaCache::new(temp_dir("cache-cleanup"), 1024);
+
+        let part = download(&cache, "a", b"OggS audio");
+        let path = cache.insert("fake", "a", &part, "https://example.com/a").unwrap();
+
+        // Interrupted download, a file without metadata and metadata without a file
+        let interrupted = download(&cache, "b", b"half");
+        let unknown = cache.media_path("fake", "unknown");
+        fs::write(&unknown, b"audio").unwrap();
+        let part = download(&cache, "c", b"OggS audio");
+        let missing = cache.insert("fake", "c", &part, "https://example.com/c").unwrap();
+        fs::remove_file(&missing).unwrap();
+
+        cache.cleanup();
+
+        assert!(path.exists());
+        assert!(!interrupted.exists());
+        assert!(!unknown.exists());
+        assert!(cache.read_entry("fake", "c").is_none());
+    }
+}
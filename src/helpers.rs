@@ -25,6 +25,15 @@ fn setup_directories_structure() -> bool{
     if !graceful_mkdir("data/cache/media") {return false;}
     if !graceful_mkdir("data/cache/meta") {return false;}
 
+    // Saved guild queues
+    if !graceful_mkdir("data/queues") {return false;}
+
+    // Guild settings
+    if !graceful_mkdir("data/settings") {return false;}
+
+    // Local files that can be played
+    if !graceful_mkdir("data/media") {return false;}
+
     true
 }
 
@@ -74,7 +83,7 @@ pub fn graceful_mkdir(dir_path: &str) -> bool {
     }
 }
 
-pub fn _write_json(file_path: &str, content: String) -> Result<()>
+pub fn write_json(file_path: &str, content: String) -> Result<()>
 {
     // Current dir to display in log
     let current_path = std::env::current_dir().unwrap();
@@ -82,18 +91,22 @@ pub fn _write_json(file_path: &str, content: String) -> Result<()>
 
     // Create the path
     let path = Path::new(file_path);
-    
+
+    // Written next to the file and renamed over it, a crash never leaves a half written file
+    let temp_path = format!("{}.tmp", file_path);
+
     // Create the file, truncates if it exist
-    match File::create(path) {
+    match File::create(&temp_path) {
         Ok(mut file) => {
             // Try to write the content
-            match file.write_all(content.as_bytes()) {
+            match file.write_all(content.as_bytes()).and_then(|_| file.sync_all()).and_then(|_| fs::rename(&temp_path, path)) {
                 Ok(_) => {
                     // Content written
                     //println!("{} File Writed: {}/{}", color::Fg(color::Cyan), current_path, path.display());
                     Ok(())
                 },
                 Err(error) => {
+                    let _ = fs::remove_file(&temp_path);
                     // Pretty error, Cannot be written
                     Err(Error::new(
                         error.kind(), 
@@ -1,4 +1,5 @@
 mod commands;
+mod idle;
 
 use once_cell::sync::Lazy;
 use anyhow::{bail, Result};
@@ -19,15 +20,37 @@ use std::{future::Future, sync::Arc};
 use crate::StateRef;
 use commands::{PlayCommand, LeaveCommand, JoinCommand};
 
-use self::commands::SkipCommand;
+use self::commands::{
+    SkipCommand, QueueCommand, LoopCommand, ShuffleCommand, RemoveCommand, MoveCommand, SwapCommand, ClearCommand, RemoveMineCommand,
+    PauseCommand, ResumeCommand, SeekCommand, VolumeCommand, NowPlayingCommand, SettingsCommand, LibraryCommand, SearchCommand
+};
+
+pub use self::commands::offer_resume;
+pub use self::idle::{IdleTracker, idle_watcher, voice_state_update};
 
 #[allow(dead_code)]
 pub static CREATE_GLOBAL_COMMANDS: Lazy<Vec<Command>> = Lazy::new(|| {
     vec![
         PlayCommand::create_command().into(),
+        SearchCommand::create_command().into(),
         SkipCommand::create_command().into(),
         JoinCommand::create_command().into(),
         LeaveCommand::create_command().into(),
+        QueueCommand::create_command().into(),
+        LoopCommand::create_command().into(),
+        ShuffleCommand::create_command().into(),
+        RemoveCommand::create_command().into(),
+        MoveCommand::create_command().into(),
+        SwapCommand::create_command().into(),
+        ClearCommand::create_command().into(),
+        RemoveMineCommand::create_command().into(),
+        PauseCommand::create_command().into(),
+        ResumeCommand::create_command().into(),
+        SeekCommand::create_command().into(),
+        VolumeCommand::create_command().into(),
+        NowPlayingCommand::create_command().into(),
+        SettingsCommand::create_command().into(),
+        LibraryCommand::create_command().into(),
     ]
 });
 
@@ -62,17 +85,151 @@ pub async fn exec_command(state: Arc<StateRef>, cmd: &Box<CommandData>, interact
             spawn(LeaveCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
             Ok(())
         },
+        "search" => {
+            spawn(SearchCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
         "skip" => {
             spawn(SkipCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
             Ok(())
-        }
+        },
+        "queue" => {
+            spawn(QueueCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "loop" => {
+            spawn(LoopCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "shuffle" => {
+            spawn(ShuffleCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "remove" => {
+            spawn(RemoveCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "move" => {
+            spawn(MoveCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "swap" => {
+            spawn(SwapCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "clear" => {
+            spawn(ClearCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "removemine" => {
+            spawn(RemoveMineCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "pause" => {
+            spawn(PauseCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "resume" => {
+            spawn(ResumeCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "seek" => {
+            spawn(SeekCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "volume" => {
+            spawn(VolumeCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "nowplaying" => {
+            spawn(NowPlayingCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "settings" => {
+            spawn(SettingsCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
+        "library" => {
+            spawn(LibraryCommand::from_interaction((**cmd).clone().into())?.run(state, interaction.0));
+            Ok(())
+        },
         _ => bail!("Unknown command interaction {}", cmd.name),
     }
 }
 
+/// Message components use `<handler>:<args>` as custom id, `awaited` when a command got it with standby
+pub async fn exec_component(state: Arc<StateRef>, custom_id: &str, interaction: Box<InteractionCreate>, awaited: bool) -> Result<()> {
+    let (handler, args) = custom_id.split_once(':').unwrap_or((custom_id, ""));
+
+    match handler {
+        "queue" => {
+            spawn(commands::queue_page_component(state, interaction.0, args.to_owned()));
+            Ok(())
+        },
+        "resume" => {
+            spawn(commands::resume_component(state, interaction.0, args.to_owned()));
+            Ok(())
+        },
+        "player" => {
+            spawn(commands::player_component(state, interaction.0, args.to_owned()));
+            Ok(())
+        },
+        // Picked while the search command waits for it with standby
+        "search" if awaited => Ok(()),
+        "search" => {
+            spawn(commands::search_expired_component(state, interaction.0));
+            Ok(())
+        },
+        "library" => {
+            spawn(commands::library_page_component(state, interaction.0, args.to_owned()));
+            Ok(())
+        },
+        _ => bail!("Unknown component interaction {}", custom_id),
+    }
+}
+
+/// Tells the user that the interaction failed, editing the response if it was already sent
+async fn send_interaction_error(info: &Arc<StateRef>, interaction: &InteractionCreate, error: &anyhow::Error) -> Result<()> {
+    let err_string = format!(
+        "An error occurred, it has been reported and will be fixed soon c:\n```\n{}\n```",
+        error
+    );
+    let client = info.http.interaction(info.application_id);
+    let msg_error = client
+        .create_response(
+            interaction.0.id,
+            &interaction.0.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(
+                    InteractionResponseDataBuilder::new()
+                        .content(err_string.clone())
+                        .flags(MessageFlags::EPHEMERAL)
+                        .build(),
+                ),
+            },
+        )
+        .await
+        .is_err();
+
+    //FIXME: maybe censor sensitive data?
+    if msg_error {
+        client
+            .update_response(&interaction.0.token)
+            .attachments(&[])?
+            .components(Some(&[]))?
+            .embeds(Some(&[]))?
+            .content(Some(&err_string))?
+            .await?;
+    }
+
+    Ok(())
+}
+
 pub async fn handle_interaction(
     interaction: Box<InteractionCreate>,
     info: Arc<StateRef>,
+    awaited: bool,
 ) -> Result<()> {
     let interaction_clone = interaction.clone();
     if let Some(data) = &interaction_clone.0.data {
@@ -82,38 +239,7 @@ pub async fn handle_interaction(
                     let command: Result<()> = exec_command(info.clone(), cmd, interaction).await;
 
                     if let Err(e) = &command {
-                        let err_string = format!(
-                            "An error occurred, it has been reported and will be fixed soon c:\n```\n{}\n```",
-                            e
-                        );
-                        let client = info.http.interaction(info.application_id);
-                        let msg_error = client
-                            .create_response(
-                                interaction_clone.0.id,
-                                &interaction_clone.0.token,
-                                &InteractionResponse {
-                                    kind: InteractionResponseType::ChannelMessageWithSource,
-                                    data: Some(
-                                        InteractionResponseDataBuilder::new()
-                                            .content(err_string.clone())
-                                            .flags(MessageFlags::EPHEMERAL)
-                                            .build(),
-                                    ),
-                                },
-                            )
-                            .await
-                            .is_err();
-
-                        //FIXME: maybe censor sensitive data?
-                        if msg_error {
-                            client
-                                .update_response(&interaction_clone.0.token)
-                                .attachments(&[])?
-                                .components(Some(&[]))?
-                                .embeds(Some(&[]))?
-                                .content(Some(&err_string))?
-                                .await?;
-                        }
+                        send_interaction_error(&info, &interaction_clone, e).await?;
                     }
 
                     let channel = interaction_clone.0.channel.clone().unwrap();
@@ -129,6 +255,21 @@ pub async fn handle_interaction(
                 }
                 _ => {}
             },
+            InteractionData::MessageComponent(component) => {
+                let command: Result<()> = exec_component(info.clone(), &component.custom_id, interaction, awaited).await;
+
+                if let Err(e) = &command {
+                    send_interaction_error(&info, &interaction_clone, e).await?;
+                }
+
+                println!(
+                    "ID: {} component by <@{}>: {}",
+                    interaction_clone.0.id,
+                    interaction_clone.0.author_id().unwrap(),
+                    component.custom_id
+                );
+                command?
+            },
             _ => {}
         }
     } else {
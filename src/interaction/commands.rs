@@ -1,27 +1,34 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use anyhow::{Result};
 use async_recursion::async_recursion;
-use tokio::sync::{Mutex, RwLock, RwLockWriteGuard};
+use tokio::sync::{Mutex, mpsc::UnboundedReceiver};
 use songbird::{
     Songbird,
     id::{ChannelId, GuildId},
-    Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent
+    Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+    error::JoinError,
+    tracks::{TrackHandle, PlayMode}
 };
 use twilight_interactions::command::{CommandModel, CreateCommand};
 use twilight_model::{
-    application::interaction::Interaction, 
+    application::interaction::{Interaction, InteractionData}, 
     http::interaction::{
         InteractionResponseType, 
         InteractionResponse
     }, 
     channel::message::{
-        MessageFlags
-    }, id::{marker::{InteractionMarker, ApplicationMarker, GuildMarker, ChannelMarker}, Id}
+        MessageFlags,
+        Embed,
+        embed::EmbedFooter,
+        Component,
+        component::{ActionRow, Button, ButtonStyle, SelectMenu, SelectMenuOption, SelectMenuType}
+    }, id::{marker::{InteractionMarker, ApplicationMarker, GuildMarker, ChannelMarker, UserMarker, RoleMarker}, Id},
+    guild::Permissions
 };
-use twilight_util::builder::{InteractionResponseDataBuilder, embed::{EmbedBuilder, ImageSource, EmbedFooterBuilder}};
+use twilight_util::builder::{InteractionResponseDataBuilder, embed::{EmbedBuilder, ImageSource, EmbedFooterBuilder, EmbedFieldBuilder}};
 use url::Url;
 
-use crate::{StateRef, pot::{PotPlayInputType, PlaylistItem, SystemPlaylist}, colour::Colour};
+use crate::{StateRef, pot::{self, PotPlayInputType, PlaylistItem, Requester, SystemPlaylist, LoopMode}, colour::Colour, settings::SettingKey, library::LibraryTrack, yt, ytdlp};
 use async_trait::async_trait;
 
 pub struct TrackEndNotifier {
@@ -29,22 +36,30 @@ pub struct TrackEndNotifier {
     channel_id: Id<ChannelMarker>,
     guild_id: Id<GuildMarker>,
     call: Arc<Mutex<Call>>,
-    playlist: Arc<RwLock<SystemPlaylist>>,
+    playlist: Arc<SystemPlaylist>,
     manager: Arc<Songbird>
 }
 
 #[async_trait]
 impl VoiceEventHandler for TrackEndNotifier {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        if let EventContext::Track(_track_list) = ctx {
-            let mut handler: tokio::sync::MutexGuard<Call> = self.call.lock().await;
-            let mut playlist = self.playlist.write().await;
-            
-            if consume_and_play_on_end(self, &mut handler, &mut playlist).await.is_none() {
+        if let EventContext::Track(track_list) = ctx {
+            // Tracks stopped by a skip or replaced by a new one also end, only the current track moves the queue.
+            // It's taken out so a second end event of the same track does nothing
+            {
+                let mut trackdata = self.state.trackdata.write().await;
+                let current = trackdata.get(&self.guild_id).map(|handle| handle.uuid());
+                if !track_list.iter().any(|(_, handle)| Some(handle.uuid()) == current) {
+                    return None
+                }
+                trackdata.remove(&self.guild_id);
+            }
+
+            // The call is only locked to play the next song, the other commands can use it while it loads
+            if consume_and_play_on_end(self, &self.playlist).await.is_none() {
                 // let _ = self.channel_id.say(&self.ctx.http(), "Queue finished").await;
                 let _ = send_queue_finished(&self.state.http, self.channel_id).await;
                 // let _ = self.channel_id.say(&self.ctx.http(), "Left voice channel").await;
-                drop(handler);
                 let _ = self.manager.remove(self.guild_id).await;
             }
         }
@@ -109,22 +124,8 @@ impl JoinCommand {
                     let author_channel = voice_state.channel_id().clone();
 
                     // Then we try to join the voice channel and return a message
-                    match state.songbird.join(guild_id, author_channel).await {
+                    match join_voice(&state, guild_id, author_channel, interaction_channel).await {
                         Ok(call_lock) => {
-                            let mut call = call_lock.lock().await;
-                            call.add_global_event(
-                                Event::Track(TrackEvent::End),
-                                TrackEndNotifier {
-                                    state: state.clone(),
-                                    channel_id: interaction_channel,
-                                    guild_id,
-                                    call: call_lock.clone(),
-                                    playlist: state.system_playlist.clone(),
-                                    manager: state.songbird.clone(),
-                                },
-                            );
-                            drop(call);
-
                             (JoinResult::Ok(format!("Joined <#{}>!", author_channel)), Some(call_lock))
                         },
                         Err(e) => (JoinResult::Err(format!("Failed to join <#{}>! Why: {:?}", author_channel, e)), None),
@@ -151,6 +152,183 @@ impl JoinCommand {
     }
 }
 
+/// Join a voice channel, register the track end notifier and remember the channels used by the guild
+async fn join_voice(
+    state: &Arc<StateRef>,
+    guild_id: Id<GuildMarker>,
+    voice_channel: Id<ChannelMarker>,
+    text_channel: Id<ChannelMarker>
+) -> Result<Arc<Mutex<Call>>, JoinError> {
+    let call_lock = state.songbird.join(guild_id, voice_channel).await?;
+    let text_channel = announce_channel(state, guild_id, text_channel).await;
+
+    let mut call = call_lock.lock().await;
+    call.add_global_event(
+        Event::Track(TrackEvent::End),
+        TrackEndNotifier {
+            state: state.clone(),
+            channel_id: text_channel,
+            guild_id,
+            call: call_lock.clone(),
+            playlist: state.system_playlist.clone(),
+            manager: state.songbird.clone(),
+        },
+    );
+    drop(call);
+
+    // Saved with the queue so it can be resumed after a restart
+    state.system_playlist.set_channels(&guild_id, voice_channel, text_channel);
+    state.idle.watch(guild_id).await;
+
+    Ok(call_lock)
+}
+
+/// Stop playing, clear the guild queue and leave the voice channel
+pub async fn leave_voice(state: &StateRef, guild_id: Id<GuildMarker>) {
+    let playlist = &state.system_playlist;
+
+    playlist.cancel(&guild_id);
+    playlist.clear(&guild_id);
+    playlist.set_status(&guild_id, false);
+
+    leave_call(state, guild_id).await;
+}
+
+/// Stop playing and leave the voice channel, the guild queue is kept so it can be resumed
+pub async fn leave_voice_keeping_queue(state: &StateRef, guild_id: Id<GuildMarker>) {
+    let playlist = &state.system_playlist;
+
+    playlist.cancel(&guild_id);
+    playlist.suspend(&guild_id);
+
+    leave_call(state, guild_id).await;
+}
+
+async fn leave_call(state: &StateRef, guild_id: Id<GuildMarker>) {
+    // The next session starts with the guild default volume
+    state.system_playlist.set_volume(&guild_id, None);
+
+    state.trackdata.write().await.remove(&guild_id);
+    close_now_playing(state, guild_id).await;
+
+    // Leave the call
+    let _ = state.songbird.remove(guild_id).await;
+}
+
+/// The channel where the bot messages of the guild go, the announce channel setting if there is one
+async fn announce_channel(state: &StateRef, guild_id: Id<GuildMarker>, fallback: Id<ChannelMarker>) -> Id<ChannelMarker> {
+    state.settings.read().await.get(&guild_id).announce_channel.unwrap_or(fallback)
+}
+
+/// Offer to resume the queues that were saved before the bot restarted
+pub async fn offer_resume(state: Arc<StateRef>) {
+    let resumable = state.system_playlist.resumable();
+
+    for (_guild_id, voice_channel, text_channel, items_count) in resumable {
+        let embed = EmbedBuilder::new()
+            .title(":musical_note:  **Resume queue?**")
+            .description(format!("I was restarted with {} songs in the queue of <#{}>", items_count, voice_channel))
+            .color(Colour::BLURPLE.0)
+            .build();
+
+        let buttons = Component::ActionRow(ActionRow {
+            components: vec![
+                button("resume:yes", "Resume", ButtonStyle::Success, false),
+                button("resume:no", "Discard", ButtonStyle::Danger, false),
+            ],
+        });
+
+        let embeds = [embed];
+        let components = [buttons];
+
+        let message = state.http
+            .create_message(text_channel)
+            .embeds(&embeds)
+            .and_then(|message| message.components(&components));
+
+        if let Ok(message) = message {
+            let _ = message.await;
+        }
+    }
+}
+
+/// Handles the buttons of the resume offer, `args` is `yes` or `no`
+pub async fn resume_component(state: Arc<StateRef>, interaction: Interaction, args: String) -> Result<()> {
+    let guild_id = match interaction.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let resume = args == "yes";
+
+    // Like the queue commands, only the users in the channel where the queue was playing can decide
+    let voice_channel = state.system_playlist.channels(&guild_id).map(|(voice_channel, _)| voice_channel);
+    let user_channel = interaction.author_id()
+        .and_then(|user_id| state.cache.voice_state(user_id, guild_id))
+        .map(|voice_state| voice_state.channel_id());
+
+    if let Some(voice_channel) = voice_channel {
+        if user_channel != Some(voice_channel) {
+            let message = format!("Join <#{}> to resume or discard the queue", voice_channel);
+            send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &message).await?;
+            return Ok(())
+        }
+    }
+
+    // Remove the buttons first so the offer can't be used twice
+    let embed = EmbedBuilder::new()
+        .title(":musical_note:  **Resume queue?**")
+        .description(if resume { "Resuming..." } else { "Queue discarded" })
+        .color(Colour::BLURPLE.0)
+        .build();
+
+    state.http
+        .interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &InteractionResponse {
+            kind: InteractionResponseType::UpdateMessage,
+            data: Some(InteractionResponseDataBuilder::new().embeds([embed]).components(Vec::<Component>::new()).build()),
+        })
+        .await?;
+
+    if !resume {
+        state.system_playlist.clear(&guild_id);
+        return Ok(())
+    }
+
+    let channels = state.system_playlist.channels(&guild_id);
+
+    let response: String = if state.songbird.get(guild_id).is_some() {
+        "Already in voice channel".into()
+    } else if let Some((voice_channel, text_channel)) = channels {
+        match join_voice(&state, guild_id, voice_channel, text_channel).await {
+            Ok(call) => {
+                if state.system_playlist.try_start(&guild_id) && consume_and_play(&state, text_channel, &state.system_playlist, guild_id, &call).await.is_none() {
+                    let _ = state.songbird.remove(guild_id).await;
+                }
+
+                format!("Resumed in <#{}>", voice_channel)
+            },
+            Err(e) => format!("Failed to join <#{}>! Why: {:?}", voice_channel, e),
+        }
+    } else {
+        "Nothing to resume".into()
+    };
+
+    let embed = EmbedBuilder::new()
+        .title(":musical_note:  **Resume queue?**")
+        .description(response)
+        .color(Colour::BLURPLE.0)
+        .build();
+
+    state.http
+        .interaction(interaction.application_id)
+        .update_response(&interaction.token)
+        .embeds(Some(&[embed]))?
+        .await?;
+
+    Ok(())
+}
+
 #[derive(CommandModel, CreateCommand)]
 #[command(name = "leave", desc = "Leave voice channel")]
 pub struct LeaveCommand;
@@ -194,15 +372,7 @@ impl LeaveCommand {
                         // Drop the call
                         drop(call);
 
-                        let mut playlist = state.system_playlist.write().await;
-
-                        playlist.clear(&guild_id);
-                        playlist.set_status(&guild_id, false);
-
-                        drop(playlist);
-
-                        // Leave the call
-                        let _ = state.songbird.remove(guild_id).await;
+                        leave_voice(&state, guild_id).await;
 
                         // Return message
                         "Disconnected".into()
@@ -236,92 +406,255 @@ pub struct PlayCommand {
 
 impl PlayCommand {
     pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
-        let guild_id: Id<GuildMarker>;
+        // Get pot input type from src
+        let input = match Url::parse(&self.song) {
+            Ok(url_parsed) => {
+                if url_parsed.host_str().unwrap_or("").ends_with("open.spotify.com") {
+                    PotPlayInputType::SpotifyUrl(url_parsed)
+                } else {
+                    PotPlayInputType::Url(url_parsed)
+                }
+            },
+            Err(_) => PotPlayInputType::Search(self.song.clone())
+        };
 
-        match interaction.guild_id {
-             // Get guild id of the interaction
-            Some(guild_id_ex) => guild_id = guild_id_ex,
-            None => {
-                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This command only works in guilds").await?;
+        play_input(state, interaction, input).await
+    }
+}
+
+/// Joins the voice channel of the user, adds the input to the queue and starts playing if nothing is playing
+async fn play_input(state: Arc<StateRef>, interaction: Interaction, input: PotPlayInputType) -> Result<()> {
+    let guild_id: Id<GuildMarker>;
+
+    match interaction.guild_id {
+         // Get guild id of the interaction
+        Some(guild_id_ex) => guild_id = guild_id_ex,
+        None => {
+            send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This command only works in guilds").await?;
+            return Ok(())
+        },
+    }
+
+    let interaction_channel_id = interaction.channel.clone().unwrap().id;
+
+    let author_name = interaction.clone().author().unwrap().clone().name;
+    let author_id = interaction.author_id().unwrap();
+    let avatar_hash: String = if let Some(hash) = interaction.author().unwrap().avatar {
+        hash.to_string()
+    } else { String::new() };
+    let avatar_url = format!("https://cdn.discordapp.com/avatars/{author_id}/{avatar_hash}.webp?size=40");
+    let requester = Requester::new(author_id, author_name, avatar_url);
+
+    let voice_state = state.cache.voice_state(author_id, guild_id);
+
+    let response_message: String = match &voice_state {
+        Some(_) => "Adding...".into(),
+        None => "Not in a voice channel".into(),
+    };
+
+    send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &response_message).await?;
+
+    let settings = state.settings.read().await.get(&guild_id);
+
+    if let Some(_voice_state) = &voice_state {
+        // Urls are only played from the hosts the guild allows
+        if let PotPlayInputType::Url(url_parsed) | PotPlayInputType::SpotifyUrl(url_parsed) = &input {
+            if !settings.is_host_allowed(url_parsed.host_str().unwrap_or("")) {
+                let _ = send_message(&state.http, interaction_channel_id, &format!("Songs from this site are not allowed here, allowed sites: {}", settings.allowed_hosts.join(", "))).await;
                 return Ok(())
+            }
+        }
+
+        let join_command = JoinCommand;
+        match join_command.run(state.clone(), interaction, true).await {
+            Ok(join_result) => {
+                if let Some(call) = join_result {
+                    // Get playlist
+                    let playlist = &state.system_playlist;
+
+                    // let channel_id = call_lock.current_channel().unwrap();
+                    // let channel_id: Id<ChannelMarker> = Id::new(channel_id.0.into());
+                    
+                    let play_channel_id = announce_channel(&state, guild_id, interaction_channel_id).await;
+
+                    // Fetched without locking the call so the current song can still be controlled
+                    match playlist.add(&guild_id, input, requester.clone(), &settings).await {
+                        Ok(added) => {
+                            tokio::spawn(pot::prefetch(state.system_playlist.clone(), guild_id));
+
+                            // A cut playlist is reported even when only one song was loaded
+                            if added.items.len() > 1 || added.warning.is_some() {
+                                let _ = send_playlist_added(&state.http, interaction_channel_id, &requester, &added.items, added.warning.as_deref()).await;
+                            } else {
+                                let _ = send_song_added(&state.http, interaction_channel_id, &requester, added.items.first().unwrap()).await;
+                            }
+            
+                            if playlist.try_start(&guild_id) && consume_and_play(&state, play_channel_id, playlist, guild_id, &call).await.is_none() {
+                                let _ = state.songbird.remove(guild_id).await;
+                                let _ = send_message(&state.http, interaction_channel_id, "Left voice channel").await;
+                            }
+                        },
+                        Err(err) => {
+                            let _ = send_message(&state.http, interaction_channel_id, &format!("Error adding to the playlist: {}", err)).await;
+                        }
+                    }
+                } else {
+                    println!("No call obtained");
+                }
+            },
+            Err(join_error) => {
+                println!("No joined fail {join_error:?}");
             },
         }
+    } else {
+        println!("No voice state");
+    }
 
-        let interaction_channel_id = interaction.channel.clone().unwrap().id;
+    Ok(())
+}
 
-        let author_name = interaction.clone().author().unwrap().clone().name;
-        let author_id = interaction.author_id().unwrap();
-        let avatar_hash: String = if let Some(hash) = interaction.author().unwrap().avatar {
-            hash.to_string()
-        } else { String::new() };
-        let avatar_url = format!("https://cdn.discordapp.com/avatars/{author_id}/{avatar_hash}.webp?size=40");
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "search", desc = "Search youtube and pick the song to play")]
+pub struct SearchCommand {
+    /// What to search
+    query: String,
+    /// Number of results to pick from, 5 by default
+    #[command(min_value = 1, max_value = 10)]
+    results: Option<i64>
+}
 
-        let voice_state = state.cache.voice_state(author_id, guild_id);
+/// How long the user has to pick a search result
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl SearchCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        if interaction.guild_id.is_none() {
+            send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This command only works in guilds").await?;
+            return Ok(())
+        }
 
-        let response_message: String = match &voice_state {
-            Some(_) => "Adding...".into(),
-            None => "Not in a voice channel".into(),
+        let author_id = interaction.author_id().unwrap();
+        let client = state.http.interaction(interaction.application_id);
+
+        // Searching takes two requests to youtube, the response is deferred so discord waits for it
+        client
+            .create_response(interaction.id, &interaction.token, &InteractionResponse {
+                kind: InteractionResponseType::DeferredChannelMessageWithSource,
+                data: None,
+            })
+            .await?;
+
+        let count = self.results.unwrap_or(5).clamp(1, 10) as u32;
+
+        let choices = match pot::search_choices(&self.query, count).await {
+            Ok(choices) if !choices.is_empty() => choices,
+            Ok(_) => {
+                client.update_response(&interaction.token).content(Some("No results found"))?.await?;
+                return Ok(())
+            },
+            Err(err) => {
+                client.update_response(&interaction.token).content(Some(&err.to_string()))?.await?;
+                return Ok(())
+            },
         };
 
-        send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &response_message).await?;
-
-        if let Some(_voice_state) = &voice_state {
-            let join_command = JoinCommand;
-            match join_command.run(state.clone(), interaction, true).await {
-                Ok(join_result) => {
-                    if let Some(call) = join_result {
-                        // Get pot input type from src
-                        let input = match Url::parse(&self.song) {
-                            Ok(url_parsed) => {
-                                if url_parsed.host_str().unwrap_or("").ends_with("open.spotify.com") {
-                                    PotPlayInputType::SpotifyUrl(url_parsed)
-                                } else {
-                                    PotPlayInputType::Url(url_parsed)
-                                }
-                            },
-                            Err(_) => PotPlayInputType::Search(self.song.clone())
-                        };
-
-                        // Get playlist
-                        let mut playlist = state.system_playlist.write().await;
-                        let mut call_lock = call.lock().await;
-    
-                        // let channel_id = call_lock.current_channel().unwrap();
-                        // let channel_id: Id<ChannelMarker> = Id::new(channel_id.0.into());
-                        
-                        match playlist.add(&guild_id, input).await {
-                            Ok((items_added_count, items_slice)) => {
-                                if items_added_count > 1 {
-                                    let _ = send_playlist_added(&state.http, interaction_channel_id, &author_name, &avatar_url, items_slice).await;
-                                } else {
-                                    let _ = send_song_added(&state.http, interaction_channel_id, &author_name, &avatar_url, items_slice.first().unwrap()).await;
-                                }
-                
-                                if !playlist.is_playing(&guild_id) && consume_and_play(&state.http, interaction_channel_id, &mut playlist, guild_id, &mut call_lock).await.is_none(){
-                                    let _ = state.songbird.remove(guild_id).await;
-                                    let _ = send_message(&state.http, interaction_channel_id, "Left voice channel").await;
-                                }
-                                drop(call_lock);
-                                drop(playlist);
-                            },
-                            Err(_err) => {
-                                let _ = send_message(&state.http, interaction_channel_id, "Error adding to the playlist").await;
-                            }
-                        }
-                    } else {
-                        println!("No call obtained");
-                    }
-                },
-                Err(join_error) => {
-                    println!("No joined fail {join_error:?}");
-                },
-            }
-        } else {
-            println!("No voice state");
+        let mut description = String::new();
+        for (index, choice) in choices.iter().enumerate() {
+            description.push_str(&format!("`{}.` [{}]({}) `{}`{}\n", index + 1, &choice.item.title, &choice.item.original_url, format_duration(choice.item.duration), choice_channel(choice)));
         }
 
-        Ok(())
+        let embed = EmbedBuilder::new()
+            .title(format!(":mag:  **Search: {}**", &self.query))
+            .description(description)
+            .footer(EmbedFooterBuilder::new("Pick a song from the menu").build())
+            .color(Colour::BLURPLE.0)
+            .build();
+
+        let options = choices.iter().enumerate().map(|(index, choice)| SelectMenuOption {
+            default: false,
+            description: Some(truncate(&format!("{}{}", format_duration(choice.item.duration), choice_channel(choice)), 100)),
+            emoji: None,
+            label: truncate(&format!("{}. {}", index + 1, &choice.item.title), 100),
+            value: index.to_string(),
+        }).collect();
+
+        let menu = Component::ActionRow(ActionRow {
+            components: vec![Component::SelectMenu(SelectMenu {
+                channel_types: None,
+                custom_id: "search:pick".to_owned(),
+                disabled: false,
+                kind: SelectMenuType::Text,
+                max_values: Some(1),
+                min_values: Some(1),
+                options: Some(options),
+                placeholder: Some("Song to play".to_owned()),
+            })],
+        });
+
+        let message = client
+            .update_response(&interaction.token)
+            .embeds(Some(&[embed.clone()]))?
+            .components(Some(&[menu]))?
+            .await?
+            .model()
+            .await?;
+
+        // Only the user that searched can pick
+        let picked = state.standby.wait_for_component(message.id, move |component: &Interaction| component.author_id() == Some(author_id));
+
+        let component = match tokio::time::timeout(SEARCH_TIMEOUT, picked).await {
+            Ok(Ok(component)) => component,
+            _ => {
+                let expired = EmbedBuilder::new()
+                    .title(format!(":mag:  **Search: {}**", &self.query))
+                    .description("The search expired")
+                    .color(Colour::DARK_GREY.0)
+                    .build();
+
+                client.update_response(&interaction.token).embeds(Some(&[expired]))?.components(Some(&[]))?.await?;
+                return Ok(())
+            },
+        };
+
+        let choice = match &component.data {
+            Some(InteractionData::MessageComponent(data)) => data.values.first().and_then(|value| value.parse::<usize>().ok()).and_then(|index| choices.get(index)),
+            _ => None,
+        };
+
+        let choice = match choice {
+            Some(choice) => choice.clone(),
+            None => return Ok(()),
+        };
+
+        // Remove the menu so the search can't be used twice
+        client.update_response(&interaction.token).embeds(Some(&[embed]))?.components(Some(&[]))?.await?;
+
+        play_input(state, component, PotPlayInputType::Items(vec![choice.item])).await
+    }
+}
+
+/// Picks nobody waits for, the search expired or was started by someone else
+pub async fn search_expired_component(state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+    send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This search expired or isn't yours, use /search to find a song").await
+}
+
+/// ` - channel` of the search result, empty when it's unknown
+fn choice_channel(choice: &pot::SearchChoice) -> String {
+    match &choice.channel {
+        Some(channel) => format!(" - {}", channel),
+        None => String::new(),
+    }
+}
+
+/// Cut the text to `max` characters, discord rejects longer select menu labels
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_owned()
     }
+
+    let mut truncated: String = text.chars().take(max - 3).collect();
+    truncated.push_str("...");
+    truncated
 }
 
 #[derive(CommandModel, CreateCommand)]
@@ -342,92 +675,189 @@ impl SkipCommand {
         }
 
         let interaction_channel_id = interaction.channel.clone().unwrap().id;
+        let author_id = interaction.author_id().unwrap();
 
-        // Get the bot call on the guild
-        match state.songbird.get(guild_id) {
-            Some(call) => {
-                // The bot is in a voice channel, we need to check if the user is in the same voice channel as the bot
+        // The user needs to be in the same voice channel as the bot
+        let call = match check_same_channel(&state, guild_id, author_id).await {
+            Ok(call) => call,
+            Err(message) => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, message).await?;
+                return Ok(())
+            },
+        };
 
-                // Get the user id
-                let author_id = interaction.author_id().unwrap();
-                // Get the voice state of the user
-                let voice_state = state.cache.voice_state(author_id, interaction.guild_id.unwrap());
+        let roles = interaction.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default();
+
+        match vote_skip(&state, guild_id, interaction_channel_id, author_id, &roles, &call).await {
+            Ok(vote) => {
+                send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, vote.embed()).await?;
+            },
+            Err(_) => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "Something happened D:").await?;
+            },
+        }
 
+        Ok(())
+    }
+}
 
-                let response: String = if let Some(voice_state) = &voice_state {
-                    // The user is in a voice channel now we need to check if the user is in the same voice channel as the bot
+/// Result of asking to skip the current song
+enum SkipVote {
+    /// The song was skipped, `instant` is true when it was skipped without counting votes
+    Skipped { message: String, votes: usize, required: usize, instant: bool },
+    /// The vote was counted but more votes are needed
+    Pending { votes: usize, required: usize }
+}
 
-                    let mut call = call.lock().await;
+impl SkipVote {
+    fn embed(&self) -> Embed {
+        let description = match self {
+            SkipVote::Skipped { message, instant: true, .. } => message.to_owned(),
+            SkipVote::Skipped { message, votes, required, .. } => format!("{} with {}/{} votes", message, votes, required),
+            SkipVote::Pending { votes, required } => format!("{}/{} votes to skip", votes, required),
+        };
 
-                    // Get user and bot voice channel id in songbird format
-                    let author_channel: ChannelId = voice_state.channel_id().clone().into();
-                    let bot_channel = call.current_channel().unwrap();
+        EmbedBuilder::new()
+            .title(":track_next:  **Skip**")
+            .description(description)
+            .color(Colour::BLURPLE.0)
+            .build()
+    }
+}
 
-                    if author_channel == bot_channel {
-                        // The user is in the same channel as the bot, we leave the call
-                        let mut playlist = state.system_playlist.write().await;
+/// Count a skip vote and skip the song when enough listeners voted,
+/// DJs and the user that requested the song skip without a vote
+async fn vote_skip(
+    state: &Arc<StateRef>,
+    guild_id: Id<GuildMarker>,
+    channel_id: Id<ChannelMarker>,
+    user_id: Id<UserMarker>,
+    roles: &[Id<RoleMarker>],
+    call: &Arc<Mutex<Call>>
+) -> Result<SkipVote> {
+    let bot_channel = call.lock().await.current_channel();
+    let listeners = bot_channel.map(|bot_channel| listeners(state, bot_channel)).unwrap_or_default();
 
-                        let result = song_skip(state.songbird.clone(), &state.http, interaction_channel_id, &mut playlist, guild_id, &mut call).await;
+    let playlist = &state.system_playlist;
 
-                        // Drop the call
-                        drop(call);
-                        drop(playlist);
+    let is_dj = dj_role(state, guild_id).await.map(|dj_role| roles.contains(&dj_role)).unwrap_or(false);
+    let is_requester = playlist.now_playing(&guild_id).map(|item| item.is_requested_by(user_id)).unwrap_or(false);
 
-                        match result {
-                            Ok(message) =>message,
-                            Err(error) => "Something happened D:".into(),
-                        }
-                    } else {
-                        // The user is not in the same voice channel and we just return a message
-                        "User not in the channel".into()
-                    }
-                } else {
-                    // The user was not in a voice channel and we just return a message
-                    "User not in a voice channel".into()
-                };
+    let votes = playlist.add_skip_vote(&guild_id, user_id, &listeners);
+    let required = required_skip_votes(listeners.len());
 
-                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &response).await?;
-            },
+    if is_dj || is_requester || votes >= required {
+        let message = song_skip(state, channel_id, playlist, guild_id, call).await?;
+        Ok(SkipVote::Skipped { message, votes, required, instant: is_dj || is_requester })
+    } else {
+        Ok(SkipVote::Pending { votes, required })
+    }
+}
+
+/// Members with this role can skip without a vote, the guild setting takes precedence over `DJ_ROLE_ID`
+async fn dj_role(state: &StateRef, guild_id: Id<GuildMarker>) -> Option<Id<RoleMarker>> {
+    if let Some(dj_role) = state.settings.read().await.get(&guild_id).dj_role {
+        return Some(dj_role)
+    }
+
+    std::env::var("DJ_ROLE_ID").ok()?.parse::<u64>().ok().and_then(Id::new_checked)
+}
+
+/// Votes needed to skip with `listeners` users in the voice channel, `VOTE_SKIP_PERCENT` of them rounded up
+fn required_skip_votes(listeners: usize) -> usize {
+    let percent = std::env::var("VOTE_SKIP_PERCENT")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(50)
+        .clamp(1, 100);
+
+    ((listeners * percent + 99) / 100).max(1)
+}
+
+/// The users that are not bots in a voice channel
+pub fn listeners(state: &StateRef, channel_id: ChannelId) -> Vec<Id<UserMarker>> {
+    let channel_id = Id::new(channel_id.0.into());
+
+    match state.cache.voice_channel_states(channel_id) {
+        Some(voice_states) => voice_states
+            .map(|voice_state| voice_state.user_id())
+            .filter(|user_id| *user_id != state.bot_id && !state.cache.user(*user_id).map(|user| user.bot).unwrap_or(false))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "queue", desc = "Show the queue")]
+pub struct QueueCommand {
+    /// Page to show
+    #[command(min_value = 1)]
+    page: Option<i64>
+}
+
+/// Number of upcoming items shown on every queue page
+const QUEUE_PAGE_SIZE: usize = 10;
+
+impl QueueCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id: Id<GuildMarker>;
+
+        match interaction.guild_id {
+             // Get guild id of the interaction
+            Some(guild_id_ex) => guild_id = guild_id_ex,
             None => {
-                // The bot is not in a voice call we just send a message
-                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "Not in voice channel").await?;
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This command only works in guilds").await?;
+                return Ok(())
             },
         }
 
+        // Pages are 1 based for the user
+        let page = self.page.unwrap_or(1).max(1) as usize - 1;
+
+        let (embed, components) = queue_view(&state.system_playlist, &guild_id, page);
+
+        let interaction_response_data = InteractionResponseDataBuilder::new()
+            .embeds([embed])
+            .components(components)
+            .build();
+
+        state.http
+            .interaction(interaction.application_id)
+            .create_response(interaction.id, &interaction.token, &InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(interaction_response_data),
+            })
+            .await?;
+
         Ok(())
     }
 }
 
-// pub async fn defer_reply(
-//     info: Arc<StateRef>,
-//     interaction: &Interaction,
-//     builder: InteractionResponseDataBuilder,
-// ) -> Result<()> {
-//     info.http
-//         .interaction(info.application_id)
-//         .create_followup(&interaction.token).content(content)
-//         .await?;
+/// Handles the previous/next buttons of a queue message, `args` is `prev:<page>` or `next:<page>`
+pub async fn queue_page_component(state: Arc<StateRef>, interaction: Interaction, args: String) -> Result<()> {
+    let guild_id = match interaction.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
 
-//     Ok(())
-// }
+    let page = args
+        .rsplit(':')
+        .next()
+        .and_then(|page| page.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let (embed, components) = queue_view(&state.system_playlist, &guild_id, page);
 
-async fn send_response(
-    http: &twilight_http::Client,
-    application_id: Id<ApplicationMarker>,
-    interaction_id: Id<InteractionMarker>,
-    interaction_token: &str,
-    response: &str
-) -> Result<()> {
     let interaction_response_data = InteractionResponseDataBuilder::new()
-        .content(response)
-        .flags(MessageFlags::EPHEMERAL)
+        .embeds([embed])
+        .components(components)
         .build();
 
-
-    http
-        .interaction(application_id)
-        .create_response(interaction_id, interaction_token, &InteractionResponse {
-            kind: InteractionResponseType::ChannelMessageWithSource,
+    // Edit the message that owns the buttons instead of sending a new one
+    state.http
+        .interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &InteractionResponse {
+            kind: InteractionResponseType::UpdateMessage,
             data: Some(interaction_response_data),
         })
         .await?;
@@ -435,48 +865,1037 @@ async fn send_response(
     Ok(())
 }
 
-#[async_recursion]
-async fn consume_and_play(
-    http: &twilight_http::Client,
-    channel_id: Id<ChannelMarker>,
-    playlist: &mut SystemPlaylist, 
-    guild_id: Id<GuildMarker>, 
-    call: &mut tokio::sync::MutexGuard<'_, Call>
-) -> Option<()> {
-    // Try to consume a item from the playlist
-    match playlist.consume(&guild_id) {
-        Some(playlist_item) => {
-            // If we found a PlaylistItem available we change the playlist status to playing
-            playlist.set_status(&guild_id, true);
-            
-            // Then we try to get the mefia file
-            match playlist.get_media(&playlist_item).await {
-                Ok(source) => {
-                    // Send message to channel
+/// Builds the queue embed for a page (0 based) and the buttons to move between pages
+fn queue_view(playlist: &SystemPlaylist, guild_id: &Id<GuildMarker>, page: usize) -> (Embed, Vec<Component>) {
+    let queue = playlist.queue(guild_id);
+    let pages = ((queue.len() + QUEUE_PAGE_SIZE - 1) / QUEUE_PAGE_SIZE).max(1);
+    // Clamp the page, the queue could have changed since the buttons were sent
+    let page = page.min(pages - 1);
 
-                    // Play the source
-                    let _ = call.play_only_input(source.into());
+    let mut description = String::new();
 
-                    let _ = send_now_playing(&http, channel_id, &playlist_item).await;
+    if let Some(item) = playlist.now_playing(guild_id) {
+        description.push_str(&format!("**Now playing**\n[{}]({}) `{}`{}\n\n", &item.title, &item.original_url, format_duration(item.duration), requested_by(item)));
+    }
+
+    if queue.is_empty() {
+        description.push_str("The queue is empty");
+    } else {
+        description.push_str("**Up next**\n");
+
+        for (index, item) in queue.iter().enumerate().skip(page * QUEUE_PAGE_SIZE).take(QUEUE_PAGE_SIZE) {
+            description.push_str(&format!("`{}.` [{}]({}) `{}`{}\n", index + 1, &item.title, &item.original_url, format_duration(item.duration), requested_by(item)));
+        }
+    }
+
+    // Songs with unknown duration count as 0
+    let runtime: f32 = queue.iter().filter_map(|item| item.duration).sum();
+    let footer = EmbedFooterBuilder::new(format!("Page {}/{} - {} songs in queue - {} total", page + 1, pages, queue.len(), format_duration(Some(runtime))))
+        .build();
+
+    let embed = EmbedBuilder::new()
+        .title(":musical_note:  **Queue**")
+        .description(description)
+        .footer(footer)
+        .color(Colour::BLURPLE.0)
+        .build();
+
+    let buttons = Component::ActionRow(ActionRow {
+        components: vec![
+            button(&format!("queue:prev:{}", page.saturating_sub(1)), "Previous", ButtonStyle::Secondary, page == 0),
+            button(&format!("queue:next:{}", page + 1), "Next", ButtonStyle::Secondary, page + 1 >= pages),
+        ],
+    });
+
+    (embed, vec![buttons])
+}
+
+/// ` - name` of the user that added the item, empty for items without requester
+fn requested_by(item: &PlaylistItem) -> String {
+    match &item.requester {
+        Some(requester) => format!(" - {}", requester.name),
+        None => String::new(),
+    }
+}
+
+fn button(custom_id: &str, label: &str, style: ButtonStyle, disabled: bool) -> Component {
+    Component::Button(Button {
+        custom_id: Some(custom_id.to_owned()),
+        disabled,
+        emoji: None,
+        label: Some(label.to_owned()),
+        style,
+        url: None,
+    })
+}
+
+/// Formats the elapsed time as a progress bar followed by `elapsed / total`
+fn format_progress(position: Duration, duration: Option<f32>) -> String {
+    const BAR_LENGTH: usize = 16;
+
+    let elapsed = position.as_secs_f32();
+    let bar: String = match duration {
+        Some(duration) if duration > 0.0 => {
+            let marker = ((elapsed / duration).min(1.0) * (BAR_LENGTH - 1) as f32) as usize;
+            (0..BAR_LENGTH).map(|index| if index == marker { '🔘' } else { '▬' }).collect()
+        },
+        _ => "▬".repeat(BAR_LENGTH),
+    };
+
+    format!("{} `{} / {}`", bar, format_duration(Some(elapsed)), format_duration(duration))
+}
+
+/// Parse a timestamp like `83`, `1:23` or `1:01:23` into a duration
+fn parse_timestamp(input: &str) -> Option<Duration> {
+    let parts = input.trim().split(':').map(|part| part.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+
+    // Every part except the first is in base 60
+    if parts.is_empty() || parts.len() > 3 || parts.iter().skip(1).any(|part| *part >= 60) {
+        return None
+    }
+
+    let seconds = parts.iter().fold(0, |total, part| total * 60 + part);
+    Some(Duration::from_secs(seconds))
+}
+
+/// Formats a duration in seconds as `h:mm:ss` or `m:ss`
+pub fn format_duration(duration: Option<f32>) -> String {
+    match duration {
+        Some(duration) => {
+            let total = duration.max(0.0) as u64;
+            let (hours, minutes, seconds) = (total / 3600, (total % 3600) / 60, total % 60);
+
+            if hours > 0 {
+                format!("{hours}:{minutes:02}:{seconds:02}")
+            } else {
+                format!("{minutes}:{seconds:02}")
+            }
+        },
+        None => "?:??".into(),
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "loop", desc = "Set the loop mode")]
+pub struct LoopCommand {
+    /// Repeat the current song, the whole queue or nothing
+    mode: LoopMode
+}
+
+impl LoopCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id: Id<GuildMarker>;
+
+        match interaction.guild_id {
+             // Get guild id of the interaction
+            Some(guild_id_ex) => guild_id = guild_id_ex,
+            None => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This command only works in guilds").await?;
+                return Ok(())
+            },
+        }
+
+        state.system_playlist.set_loop_mode(&guild_id, self.mode);
+        refresh_now_playing(&state, guild_id).await;
+
+        let description = match self.mode {
+            LoopMode::Off => "Loop disabled",
+            LoopMode::Track => "Looping the current song",
+            LoopMode::Queue => "Looping the queue",
+        };
+
+        let embed = EmbedBuilder::new()
+            .title(":repeat:  **Loop mode changed**")
+            .description(description)
+            .color(Colour::BLURPLE.0)
+            .build();
+
+        send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "shuffle", desc = "Shuffle the queue")]
+pub struct ShuffleCommand;
+
+impl ShuffleCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        let shuffled = state.system_playlist.shuffle(&guild_id);
+
+        let embed = EmbedBuilder::new()
+            .title(":twisted_rightwards_arrows:  **Queue shuffled**")
+            .description(format!("{} songs shuffled", shuffled))
+            .color(Colour::BLURPLE.0)
+            .build();
+
+        send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "remove", desc = "Remove songs from the queue")]
+pub struct RemoveCommand {
+    /// Position of the song or a range of positions like 3-7
+    position: String
+}
+
+impl RemoveCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        let (start, end) = match parse_position_range(&self.position) {
+            Some(range) => range,
+            None => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "Use a position like `3` or a range like `3-7`").await?;
+                return Ok(())
+            },
+        };
+
+        let result = state.system_playlist.remove(&guild_id, start, end);
+
+        match result {
+            Ok(removed) => {
+                let description = if let [item] = removed.as_slice() {
+                    format!("[{}]({})", &item.title, &item.original_url)
+                } else {
+                    format!("{} songs removed from positions {} to {}", removed.len(), start + 1, end + 1)
+                };
+
+                let embed = EmbedBuilder::new()
+                    .title(":wastebasket:  **Removed from queue**")
+                    .description(description)
+                    .color(Colour::BLURPLE.0)
+                    .build();
+
+                send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+            },
+            Err(err) => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &err.to_string()).await?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "move", desc = "Move a song to another position of the queue")]
+pub struct MoveCommand {
+    /// Current position of the song
+    #[command(min_value = 1)]
+    from: i64,
+    /// New position of the song
+    #[command(min_value = 1)]
+    to: i64
+}
+
+impl MoveCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        let result = state.system_playlist.move_item(&guild_id, to_index(self.from), to_index(self.to));
+
+        match result {
+            Ok(item) => {
+                let embed = EmbedBuilder::new()
+                    .title(":arrow_right_hook:  **Song moved**")
+                    .description(format!("[{}]({}) moved from position {} to {}", &item.title, &item.original_url, self.from, self.to))
+                    .color(Colour::BLURPLE.0)
+                    .build();
+
+                send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+            },
+            Err(err) => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &err.to_string()).await?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "swap", desc = "Swap the position of two songs of the queue")]
+pub struct SwapCommand {
+    /// Position of the first song
+    #[command(min_value = 1)]
+    a: i64,
+    /// Position of the second song
+    #[command(min_value = 1)]
+    b: i64
+}
+
+impl SwapCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        let result = state.system_playlist.swap(&guild_id, to_index(self.a), to_index(self.b));
+
+        match result {
+            Ok((item_a, item_b)) => {
+                let embed = EmbedBuilder::new()
+                    .title(":left_right_arrow:  **Songs swapped**")
+                    .description(format!(
+                        "`{}.` [{}]({})\n`{}.` [{}]({})",
+                        self.a, &item_a.title, &item_a.original_url,
+                        self.b, &item_b.title, &item_b.original_url
+                    ))
+                    .color(Colour::BLURPLE.0)
+                    .build();
+
+                send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+            },
+            Err(err) => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &err.to_string()).await?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "clear", desc = "Remove every song from the queue")]
+pub struct ClearCommand;
+
+impl ClearCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        let cleared = state.system_playlist.queue(&guild_id).len();
+        state.system_playlist.clear(&guild_id);
+
+        let embed = EmbedBuilder::new()
+            .title(":wastebasket:  **Queue cleared**")
+            .description(format!("{} songs removed from the queue", cleared))
+            .color(Colour::BLURPLE.0)
+            .build();
+
+        send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "removemine", desc = "Remove the songs you added from the queue")]
+pub struct RemoveMineCommand;
+
+impl RemoveMineCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        let author_id = interaction.author_id().unwrap();
+        let removed = state.system_playlist.remove_requested_by(&guild_id, author_id);
+
+        if removed.is_empty() {
+            send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "You have no songs in the queue").await?;
+            return Ok(())
+        }
+
+        let embed = EmbedBuilder::new()
+            .title(":wastebasket:  **Removed your songs**")
+            .description(format!("{} songs removed from the queue", removed.len()))
+            .color(Colour::BLURPLE.0)
+            .build();
+
+        send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "pause", desc = "Pause the current song")]
+pub struct PauseCommand;
+
+impl PauseCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        match current_track(&state, guild_id).await.map(|handle| handle.pause()) {
+            Some(Ok(_)) => {
+                refresh_now_playing(&state, guild_id).await;
+
+                let embed = EmbedBuilder::new()
+                    .title(":pause_button:  **Paused**")
+                    .color(Colour::BLURPLE.0)
+                    .build();
+
+                send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+            },
+            _ => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "Nothing to pause").await?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "resume", desc = "Resume the current song")]
+pub struct ResumeCommand;
+
+impl ResumeCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        match current_track(&state, guild_id).await.map(|handle| handle.play()) {
+            Some(Ok(_)) => {
+                refresh_now_playing(&state, guild_id).await;
+
+                let embed = EmbedBuilder::new()
+                    .title(":arrow_forward:  **Resumed**")
+                    .color(Colour::BLURPLE.0)
+                    .build();
+
+                send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+            },
+            _ => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "Nothing to resume").await?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "seek", desc = "Jump to a position of the current song")]
+pub struct SeekCommand {
+    /// Position like 1:23
+    position: String
+}
+
+impl SeekCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        let position = match parse_timestamp(&self.position) {
+            Some(position) => position,
+            None => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "Use a position like `1:23`").await?;
+                return Ok(())
+            },
+        };
+
+        let duration = state.system_playlist.now_playing(&guild_id).and_then(|item| item.duration);
+        if let Some(duration) = duration {
+            if position.as_secs_f32() >= duration {
+                let message = format!("The song is only {} long", format_duration(Some(duration)));
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &message).await?;
+                return Ok(())
+            }
+        }
+
+        match current_track(&state, guild_id).await {
+            Some(handle) => {
+                // Songs streamed from yt-dlp and radios are read from a pipe, they can't go back or jump ahead
+                if let Err(err) = handle.seek_async(position).await {
+                    tracing::debug!("cannot seek: {:?}", err);
+                    let message = "This song is being streamed and can't be seeked, it can once it's cached the next time it plays";
+                    send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, message).await?;
+                    return Ok(())
+                }
+
+                let embed = EmbedBuilder::new()
+                    .title(":fast_forward:  **Seeked**")
+                    .description(format!("Playing from {}", format_duration(Some(position.as_secs_f32()))))
+                    .color(Colour::BLURPLE.0)
+                    .build();
+
+                send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+            },
+            None => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "Nothing is playing").await?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "volume", desc = "Change the volume")]
+pub struct VolumeCommand {
+    /// Volume in percent
+    #[command(min_value = 0, max_value = 200)]
+    volume: i64
+}
+
+impl VolumeCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match queue_command_guard(&state, &interaction).await? {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+
+        let volume = self.volume.clamp(0, 200) as f32 / 100.0;
+
+        // Keep the volume for the next songs too
+        state.system_playlist.set_volume(&guild_id, Some(volume));
+
+        if let Some(handle) = current_track(&state, guild_id).await {
+            let _ = handle.set_volume(volume);
+        }
+
+        let embed = EmbedBuilder::new()
+            .title(":loud_sound:  **Volume changed**")
+            .description(format!("Volume set to {}%", self.volume.clamp(0, 200)))
+            .color(Colour::BLURPLE.0)
+            .build();
+
+        send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "nowplaying", desc = "Show the current song")]
+pub struct NowPlayingCommand;
+
+impl NowPlayingCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id: Id<GuildMarker>;
+
+        match interaction.guild_id {
+             // Get guild id of the interaction
+            Some(guild_id_ex) => guild_id = guild_id_ex,
+            None => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This command only works in guilds").await?;
+                return Ok(())
+            },
+        }
+
+        let item = state.system_playlist.now_playing(&guild_id);
+        let loop_mode = state.system_playlist.loop_mode(&guild_id);
+
+        let item = match item {
+            Some(item) => item,
+            None => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "Nothing is playing").await?;
+                return Ok(())
+            },
+        };
+
+        let position = match current_track(&state, guild_id).await {
+            Some(handle) => handle.get_info().await.ok().map(|info| info.position),
+            None => None,
+        };
+
+        let embed = now_playing_embed(&item, loop_mode, Some(position.unwrap_or_default()));
+
+        send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "settings", desc = "Guild settings, only for admins")]
+pub enum SettingsCommand {
+    #[command(name = "get")]
+    Get(SettingsGetCommand),
+    #[command(name = "set")]
+    Set(SettingsSetCommand),
+    #[command(name = "reset")]
+    Reset(SettingsResetCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "get", desc = "Show the guild settings")]
+pub struct SettingsGetCommand;
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "set", desc = "Change a guild setting")]
+pub struct SettingsSetCommand {
+    /// Setting to change
+    setting: SettingKey,
+    /// New value, durations like 10:00 and hosts separated by commas
+    value: String
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "reset", desc = "Go back to the default settings")]
+pub struct SettingsResetCommand {
+    /// Setting to reset, all of them if empty
+    setting: Option<SettingKey>
+}
+
+impl SettingsCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        let guild_id = match interaction.guild_id {
+            Some(guild_id) => guild_id,
+            None => {
+                send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This command only works in guilds").await?;
+                return Ok(())
+            },
+        };
+
+        // Only members that can manage the guild can see and change the settings
+        let is_admin = interaction.member
+            .as_ref()
+            .and_then(|member| member.permissions)
+            .map(|permissions| permissions.intersects(Permissions::ADMINISTRATOR | Permissions::MANAGE_GUILD))
+            .unwrap_or(false);
+
+        if !is_admin {
+            send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "You need the Manage Server permission to use this command").await?;
+            return Ok(())
+        }
+
+        let description = match self {
+            SettingsCommand::Get(_) => None,
+            SettingsCommand::Set(set) => {
+                if let Err(err) = state.settings.write().await.set(&guild_id, set.setting, &set.value) {
+                    send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, &err.to_string()).await?;
+                    return Ok(())
+                }
+                Some(format!("{} changed", set.setting.name()))
+            },
+            SettingsCommand::Reset(reset) => {
+                state.settings.write().await.reset(&guild_id, reset.setting);
+                match reset.setting {
+                    Some(setting) => Some(format!("{} reset", setting.name())),
+                    None => Some("All the settings were reset".into()),
+                }
+            },
+        };
+
+        let settings = state.settings.read().await.get(&guild_id);
+
+        let mut embed = EmbedBuilder::new()
+            .title(":gear:  **Settings**")
+            .color(Colour::BLURPLE.0);
+
+        if let Some(description) = description {
+            embed = embed.description(description);
+        }
+
+        for key in SettingKey::ALL {
+            embed = embed.field(EmbedFieldBuilder::new(key.name(), settings.display(key)).inline());
+        }
+
+        embed = embed.footer(EmbedFooterBuilder::new(youtube_usage()));
+
+        send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed.build()).await?;
+
+        Ok(())
+    }
+}
+
+/// Youtube quota the whole bot spent today and the links yt-dlp resolved instead,
+/// shown with the settings so the admins can keep an eye on them
+fn youtube_usage() -> String {
+    let used = yt::quota_used_today();
+    let total: u64 = used.values().sum();
+
+    let mut endpoints: Vec<String> = used.iter().map(|(endpoint, units)| format!("{} {}", endpoint, units)).collect();
+    endpoints.sort();
+
+    let quota = if endpoints.is_empty() {
+        "Youtube quota used today: 0 units".to_owned()
+    } else {
+        format!("Youtube quota used today: {} units ({})", total, endpoints.join(", "))
+    };
+
+    format!("{}\nYoutube links resolved with yt-dlp since the start: {}", quota, pot::youtube_fallbacks())
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "library", desc = "Songs from the local library")]
+pub enum LibraryCommand {
+    #[command(name = "search")]
+    Search(LibrarySearchCommand),
+    #[command(name = "play")]
+    Play(LibraryPlayCommand),
+    #[command(name = "list")]
+    List(LibraryListCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "search", desc = "Find songs in the library")]
+pub struct LibrarySearchCommand {
+    /// Words of the title, artist, album or file name
+    query: String
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "play", desc = "Play songs from the library")]
+pub struct LibraryPlayCommand {
+    /// Words of the title, artist, album or file name
+    query: String,
+    /// Add every match instead of only the first one
+    all: Option<bool>
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name = "list", desc = "List the songs in the library")]
+pub struct LibraryListCommand {
+    /// Page to show
+    #[command(min_value = 1)]
+    page: Option<i64>
+}
+
+/// Number of songs shown on every library page
+const LIBRARY_PAGE_SIZE: usize = 10;
+
+impl LibraryCommand {
+    pub async fn run(self, state: Arc<StateRef>, interaction: Interaction) -> Result<()> {
+        // Scanned in the background so the interaction is answered in time, new files show up on the next command
+        {
+            let library = state.library.clone();
+            tokio::spawn(async move { library.refresh().await });
+        }
+
+        match self {
+            LibraryCommand::Search(search) => {
+                let tracks = state.library.search(&search.query);
+
+                let (embed, _) = library_view(&tracks, &format!(":mag:  **Library search: {}**", &search.query), 0, "");
+                send_embed_response(&state.http, interaction.application_id, interaction.id, &interaction.token, embed).await?;
+            },
+            LibraryCommand::Play(play) => {
+                let mut tracks = state.library.search(&play.query);
+
+                if tracks.is_empty() {
+                    send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "No songs in the library match the search").await?;
+                    return Ok(())
+                }
+
+                if !play.all.unwrap_or(false) {
+                    tracks.truncate(1);
+                }
+
+                let items = tracks.iter().map(|track| track.playlist_item()).collect();
+                play_input(state, interaction, PotPlayInputType::Items(items)).await?;
+            },
+            LibraryCommand::List(list) => {
+                // Pages are 1 based for the user
+                let page = list.page.unwrap_or(1).max(1) as usize - 1;
+
+                let (embed, components) = library_view(&state.library.tracks(), ":file_folder:  **Library**", page, "library");
+
+                let interaction_response_data = InteractionResponseDataBuilder::new()
+                    .embeds([embed])
+                    .components(components)
+                    .build();
+
+                state.http
+                    .interaction(interaction.application_id)
+                    .create_response(interaction.id, &interaction.token, &InteractionResponse {
+                        kind: InteractionResponseType::ChannelMessageWithSource,
+                        data: Some(interaction_response_data),
+                    })
+                    .await?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+/// Previous and next buttons of the `/library list` message
+pub async fn library_page_component(state: Arc<StateRef>, interaction: Interaction, args: String) -> Result<()> {
+    let page = args
+        .rsplit(':')
+        .next()
+        .and_then(|page| page.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let (embed, components) = library_view(&state.library.tracks(), ":file_folder:  **Library**", page, "library");
+
+    let interaction_response_data = InteractionResponseDataBuilder::new()
+        .embeds([embed])
+        .components(components)
+        .build();
+
+    // Edit the message that owns the buttons instead of sending a new one
+    state.http
+        .interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &InteractionResponse {
+            kind: InteractionResponseType::UpdateMessage,
+            data: Some(interaction_response_data),
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Builds the embed with a page (0 based) of the tracks, the buttons to move between pages
+/// use `handler` as custom id and there are no buttons when it's empty
+fn library_view(tracks: &[LibraryTrack], title: &str, page: usize, handler: &str) -> (Embed, Vec<Component>) {
+    let pages = ((tracks.len() + LIBRARY_PAGE_SIZE - 1) / LIBRARY_PAGE_SIZE).max(1);
+    // Clamp the page, files could have been removed since the buttons were sent
+    let page = page.min(pages - 1);
+
+    let mut description = String::new();
+
+    if tracks.is_empty() {
+        description.push_str("No songs found");
+    }
+
+    for (index, track) in tracks.iter().enumerate().skip(page * LIBRARY_PAGE_SIZE).take(LIBRARY_PAGE_SIZE) {
+        let album = track.album.as_ref().map(|album| format!(" - *{}*", album)).unwrap_or_default();
+        description.push_str(&format!("`{}.` {} `{}`{}\n", index + 1, track.display_title(), format_duration(track.duration), album));
+    }
+
+    let footer = EmbedFooterBuilder::new(format!("Page {}/{} - {} songs", page + 1, pages, tracks.len()))
+        .build();
+
+    let embed = EmbedBuilder::new()
+        .title(title)
+        .description(description)
+        .footer(footer)
+        .color(Colour::BLURPLE.0)
+        .build();
+
+    if handler.is_empty() {
+        return (embed, Vec::new())
+    }
+
+    let buttons = Component::ActionRow(ActionRow {
+        components: vec![
+            button(&format!("{}:prev:{}", handler, page.saturating_sub(1)), "Previous", ButtonStyle::Secondary, page == 0),
+            button(&format!("{}:next:{}", handler, page + 1), "Next", ButtonStyle::Secondary, page + 1 >= pages),
+        ],
+    });
+
+    (embed, vec![buttons])
+}
+
+/// Returns the handle of the track that is playing in the guild
+pub async fn current_track(state: &StateRef, guild_id: Id<GuildMarker>) -> Option<TrackHandle> {
+    state.trackdata.read().await.get(&guild_id).cloned()
+}
+
+/// Commands that change the queue only work in guilds and for users listening with the bot,
+/// returns None after telling the user why when that's not the case
+async fn queue_command_guard(state: &StateRef, interaction: &Interaction) -> Result<Option<Id<GuildMarker>>> {
+    let guild_id = match interaction.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, "This command only works in guilds").await?;
+            return Ok(None)
+        },
+    };
+
+    match check_same_channel(state, guild_id, interaction.author_id().unwrap()).await {
+        Ok(_) => Ok(Some(guild_id)),
+        Err(message) => {
+            send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, message).await?;
+            Ok(None)
+        },
+    }
+}
+
+/// Checks that the bot is in a voice channel of the guild and the user is in the same channel,
+/// returns the bot call or the message to show to the user
+async fn check_same_channel(state: &StateRef, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Result<Arc<Mutex<Call>>, &'static str> {
+    // Get the bot call on the guild
+    let call = state.songbird.get(guild_id).ok_or("Not in voice channel")?;
+
+    // Get user voice channel id in songbird format
+    let author_channel: ChannelId = match state.cache.voice_state(user_id, guild_id) {
+        Some(voice_state) => voice_state.channel_id().into(),
+        None => return Err("User not in a voice channel"),
+    };
+
+    let bot_channel = call.lock().await.current_channel();
+
+    if bot_channel == Some(author_channel) {
+        Ok(call)
+    } else {
+        Err("User not in the channel")
+    }
+}
+
+/// Parse a 1 based position like `3` or range like `3-7` into a 0 based inclusive range
+fn parse_position_range(input: &str) -> Option<(usize, usize)> {
+    let (start, end) = match input.split_once('-') {
+        Some((start, end)) => (start.trim().parse::<usize>().ok()?, end.trim().parse::<usize>().ok()?),
+        None => {
+            let position = input.trim().parse::<usize>().ok()?;
+            (position, position)
+        },
+    };
+
+    if start == 0 || end == 0 { return None }
+
+    Some((start - 1, end - 1))
+}
+
+/// Convert a 1 based position from a command option to a 0 based index
+fn to_index(position: i64) -> usize {
+    (position.max(1) - 1) as usize
+}
+
+// pub async fn defer_reply(
+//     info: Arc<StateRef>,
+//     interaction: &Interaction,
+//     builder: InteractionResponseDataBuilder,
+// ) -> Result<()> {
+//     info.http
+//         .interaction(info.application_id)
+//         .create_followup(&interaction.token).content(content)
+//         .await?;
+
+//     Ok(())
+// }
+
+async fn send_response(
+    http: &twilight_http::Client,
+    application_id: Id<ApplicationMarker>,
+    interaction_id: Id<InteractionMarker>,
+    interaction_token: &str,
+    response: &str
+) -> Result<()> {
+    let interaction_response_data = InteractionResponseDataBuilder::new()
+        .content(response)
+        .flags(MessageFlags::EPHEMERAL)
+        .build();
+
+
+    http
+        .interaction(application_id)
+        .create_response(interaction_id, interaction_token, &InteractionResponse {
+            kind: InteractionResponseType::ChannelMessageWithSource,
+            data: Some(interaction_response_data),
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn send_embed_response(
+    http: &twilight_http::Client,
+    application_id: Id<ApplicationMarker>,
+    interaction_id: Id<InteractionMarker>,
+    interaction_token: &str,
+    embed: Embed
+) -> Result<()> {
+    let interaction_response_data = InteractionResponseDataBuilder::new()
+        .embeds([embed])
+        .build();
+
+    http
+        .interaction(application_id)
+        .create_response(interaction_id, interaction_token, &InteractionResponse {
+            kind: InteractionResponseType::ChannelMessageWithSource,
+            data: Some(interaction_response_data),
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Volume for the next track, the one set with /volume or the guild default volume
+async fn track_volume(state: &StateRef, playlist: &SystemPlaylist, guild_id: Id<GuildMarker>) -> f32 {
+    match playlist.volume(&guild_id) {
+        Some(volume) => volume,
+        None => state.settings.read().await.get(&guild_id).default_volume.map(|volume| volume as f32 / 100.0).unwrap_or(1.0),
+    }
+}
+
+#[async_recursion]
+async fn consume_and_play(
+    state: &Arc<StateRef>,
+    channel_id: Id<ChannelMarker>,
+    playlist: &SystemPlaylist, 
+    guild_id: Id<GuildMarker>, 
+    call: &Arc<Mutex<Call>>
+) -> Option<()> {
+    // Try to consume a item from the playlist
+    match playlist.consume(&guild_id, false) {
+        Some(playlist_item) => {
+            // If we found a PlaylistItem available we change the playlist status to playing
+            playlist.set_status(&guild_id, true);
+
+            // Search the item if the prefetcher didn't do it yet, get_media reports the error if it fails
+            let playlist_item = playlist.resolve_now_playing(&guild_id).await.unwrap_or(playlist_item);
+            tokio::spawn(pot::prefetch(state.system_playlist.clone(), guild_id));
+
+            // Searches are added before their duration and site are known
+            if let Some(message) = unplayable_message(state, guild_id, &playlist_item).await {
+                playlist.set_status(&guild_id, false);
+                let _ = send_message(&state.http, channel_id, &message).await;
+                return consume_and_play(state, channel_id, playlist, guild_id, call).await
+            }
+            
+            // Then we try to get the mefia file
+            match playlist.get_media(&guild_id, &playlist_item).await {
+                Ok(media) => {
+                    // Send message to channel
+
+                    // Play the source and keep the handle to control it later, the call is only locked for this
+                    let track_handle = call.lock().await.play_only_input(media.input.into());
+                    let _ = track_handle.set_volume(track_volume(state, playlist, guild_id).await);
+                    seek_to_start(&track_handle, media.seek_to);
+                    state.trackdata.write().await.insert(guild_id, track_handle);
+
+                    if let Some(titles) = media.titles {
+                        tokio::spawn(follow_stream_titles(state.clone(), guild_id, playlist_item.original_url.to_owned(), titles));
+                    }
+
+                    update_now_playing(state, guild_id, channel_id, &playlist_item, playlist.loop_mode(&guild_id), false).await;
                     Some(())
                 },
                 Err(err) => {
-                    println!("{:?}", err);
+                    tracing::debug!("cannot play {}: {:?}", playlist_item.original_url, err);
                     // Set status to not playing
                     playlist.set_status(&guild_id, false);
+                    // The bot left or the song was skipped while loading, nothing else should play
+                    if ytdlp::is_cancelled(&err) { return None }
                     // Send message of error
-                    let _ = send_message(http, channel_id, &format!("Cannot play {}", playlist_item.title)).await;
+                    let _ = send_message(&state.http, channel_id, &format!("Cannot play {}: {}", playlist_item.title, err)).await;
                     // Try again
-                    consume_and_play(&http, channel_id, playlist, guild_id, call).await
+                    consume_and_play(state, channel_id, playlist, guild_id, call).await
                 }
             }
         },
         None => {
             // No more items in playlist
             // let _ = channel_id.say(&http, "Queue finished").await;
-            let _ = send_queue_finished(&http, channel_id).await;
+            let _ = send_queue_finished(&state.http, channel_id).await;
             // Set status to not playing
             playlist.set_status(&guild_id, false);
+            state.trackdata.write().await.remove(&guild_id);
+            close_now_playing(state, guild_id).await;
             None
         }
     }
@@ -485,49 +1904,109 @@ async fn consume_and_play(
 #[async_recursion]
 pub async fn consume_and_play_on_end (
     slf: &TrackEndNotifier, 
-    call: &mut tokio::sync::MutexGuard<'_, Call>, 
-    playlist: &mut RwLockWriteGuard<SystemPlaylist>
+    playlist: &SystemPlaylist
 ) -> Option<()> {
-    match playlist.consume(&slf.guild_id) {
+    match playlist.consume(&slf.guild_id, true) {
         Some(item) => {
-            println!("consumed");
-            match playlist.get_media(&item).await {
-                Ok(source) => {
-                    println!("media getted");
+            let item = playlist.resolve_now_playing(&slf.guild_id).await.unwrap_or(item);
+            tokio::spawn(pot::prefetch(slf.playlist.clone(), slf.guild_id));
+
+            if let Some(message) = unplayable_message(&slf.state, slf.guild_id, &item).await {
+                playlist.set_status(&slf.guild_id, false);
+                let _ = send_message(&slf.state.http, slf.channel_id, &message).await;
+                return consume_and_play_on_end(slf, playlist).await
+            }
+
+            match playlist.get_media(&slf.guild_id, &item).await {
+                Ok(media) => {
+                    playlist.set_status(&slf.guild_id, true);
 
-                    call.play_only_input(source.into());
-                    let _ = send_now_playing_on_end(&slf, &item).await;
+                    let track_handle = slf.call.lock().await.play_only_input(media.input.into());
+                    let _ = track_handle.set_volume(track_volume(&slf.state, playlist, slf.guild_id).await);
+                    seek_to_start(&track_handle, media.seek_to);
+                    slf.state.trackdata.write().await.insert(slf.guild_id, track_handle);
+
+                    if let Some(titles) = media.titles {
+                        tokio::spawn(follow_stream_titles(slf.state.clone(), slf.guild_id, item.original_url.to_owned(), titles));
+                    }
+
+                    update_now_playing(&slf.state, slf.guild_id, slf.channel_id, &item, playlist.loop_mode(&slf.guild_id), false).await;
                     Some(())
                 },
                 Err(err) => {
-                    println!("{:?}", err);
-                    println!("media not getted");
-                    let _ = send_cannot_play_on_end(&slf, &item).await;
-                    consume_and_play_on_end(slf, call, playlist).await
+                    tracing::debug!("cannot play {}: {:?}", item.original_url, err);
+                    // Forget the failed item so the loop mode doesn't play it again
+                    playlist.set_status(&slf.guild_id, false);
+                    if ytdlp::is_cancelled(&err) { return None }
+                    let _ = send_cannot_play_on_end(&slf, &item, &err).await;
+                    consume_and_play_on_end(slf, playlist).await
                 },
             }
         },
         None => {
             playlist.set_status(&slf.guild_id, false);
+            slf.state.trackdata.write().await.remove(&slf.guild_id);
+            close_now_playing(&slf.state, slf.guild_id).await;
             None
         },
     }
 }
 
+/// Message for an item longer than the max song duration of the guild or from a site it doesn't allow,
+/// None when it can be played
+async fn unplayable_message(state: &StateRef, guild_id: Id<GuildMarker>, item: &PlaylistItem) -> Option<String> {
+    let settings = state.settings.read().await.get(&guild_id);
+
+    if !settings.is_duration_allowed(item.duration) {
+        return Some(format!("Skipped {}, songs longer than {} can't be played", &item.title, settings.display(SettingKey::MaxSongDuration)))
+    }
+
+    if !settings.is_url_allowed(&item.original_url) {
+        return Some(format!("Skipped {}, songs from this site are not allowed here, allowed sites: {}", &item.title, settings.allowed_hosts.join(", ")))
+    }
+
+    None
+}
+
+/// Seek a track that just started to the start time of its url, when it fails the song plays from the beginning
+fn seek_to_start(handle: &TrackHandle, position: Option<Duration>) {
+    if let Some(position) = position {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle.seek_async(position).await {
+                tracing::warn!("cannot seek to the start time: {:?}", err);
+            }
+        });
+    }
+}
+
+/// Keep the now playing panel showing the song on air while the radio plays
+async fn follow_stream_titles(state: Arc<StateRef>, guild_id: Id<GuildMarker>, url: String, mut titles: UnboundedReceiver<String>) {
+    while let Some(title) = titles.recv().await {
+        if !state.system_playlist.set_stream_title(&guild_id, &url, &title) {
+            break
+        }
+
+        refresh_now_playing(&state, guild_id).await;
+    }
+}
+
 pub async fn song_skip(
-    songbird: Arc<Songbird>,
-    http: &twilight_http::Client,
+    state: &Arc<StateRef>,
     channel_id: Id<ChannelMarker>,
-    playlist: &mut SystemPlaylist, 
+    playlist: &SystemPlaylist, 
     guild_id: Id<GuildMarker>, 
-    call: &mut tokio::sync::MutexGuard<'_, Call>
+    call: &Arc<Mutex<Call>>
 ) -> Result<String> {
-    call.stop();
+    // Whatever yt-dlp is doing for the current song is not needed anymore
+    playlist.cancel(&guild_id);
+    // Taken out first so the end of the stopped track doesn't move the queue again
+    state.trackdata.write().await.remove(&guild_id);
+    call.lock().await.stop();
 
     if playlist.is_playing(&guild_id) {
-        if consume_and_play(http, channel_id, playlist, guild_id, call).await.is_none() {
-            drop(call);
-            let _ = songbird.remove(guild_id).await;
+        if consume_and_play(state, channel_id, playlist, guild_id, call).await.is_none() {
+            let _ = state.songbird.remove(guild_id).await;
             Ok("Queue ended".into())
         } else {
             Ok("Song skipped".into())
@@ -537,7 +2016,7 @@ pub async fn song_skip(
     }
 }
 
-async fn send_message(
+pub async fn send_message(
     http: &twilight_http::Client,
     channel_id: Id<ChannelMarker>,
     message: &str
@@ -553,18 +2032,21 @@ async fn send_message(
 async fn send_playlist_added(
     http: &twilight_http::Client,
     channel_id: Id<ChannelMarker>,
-    user_name: &str,
-    avatar_url: &str,
-    items: &[PlaylistItem]
+    requester: &Requester,
+    items: &[PlaylistItem],
+    warning: Option<&str>
 ) -> Result<()> {
 
-    let footer = EmbedFooterBuilder::new(format!("Requested by {}", user_name))
-        .icon_url(ImageSource::url(avatar_url).unwrap())
-        .build();
+    let footer = requester_footer(requester, None);
+
+    let mut description = format!("{} elements added to playlist", &items.len());
+    if let Some(warning) = warning {
+        description.push_str(&format!("\n{}", warning));
+    }
 
     let embed = EmbedBuilder::new()
         .title(":musical_note:  **Playlist added to queue**")
-        .description(format!("{} elements added to playlist", &items.len()))
+        .description(description)
         .footer(footer)
         .build();
 
@@ -581,55 +2063,243 @@ async fn send_playlist_added(
 async fn send_song_added(
     http: &twilight_http::Client,
     channel_id: Id<ChannelMarker>,
-    user_name: &str,
-    avatar_url: &str,
+    requester: &Requester,
     item: &PlaylistItem
 ) -> Result<()> {
-    let thumbnail = item.thumbnail.as_ref().unwrap_or(&String::new()).to_owned();
-
-    let footer = EmbedFooterBuilder::new(format!("Requested by {}", user_name))
-        .icon_url(ImageSource::url(avatar_url).unwrap())
-        .build();
-
-    let embed = EmbedBuilder::new()
-        .title(":musical_note:  **Song added to queue**")
-        .description(format!("[{}]({})", &item.title, &item.original_url))
-        .thumbnail(ImageSource::url(thumbnail).unwrap())
-        .footer(footer)
-        .build();
-
     http
         .create_message(channel_id)
         .embeds(&[
-            embed
+            song_added_embed(requester, item)
         ]).unwrap()
         .await?;
 
     Ok(())
 }
 
+fn song_added_embed(requester: &Requester, item: &PlaylistItem) -> Embed {
+    let embed = EmbedBuilder::new()
+        .title(":musical_note:  **Song added to queue**")
+        .description(format!("[{}]({})", &item.title, &item.original_url))
+        .footer(requester_footer(requester, None));
+
+    with_thumbnail(embed, item).build()
+}
+
+/// Set the thumbnail of the item, searches, radios and local files have none
+fn with_thumbnail(embed: EmbedBuilder, item: &PlaylistItem) -> EmbedBuilder {
+    match item.thumbnail.as_deref().map(ImageSource::url) {
+        Some(Ok(thumbnail)) => embed.thumbnail(thumbnail),
+        _ => embed,
+    }
+}
+
+
+/// `Requested by <name>` footer with the avatar of the user, `extra` is appended after a dash
+fn requester_footer(requester: &Requester, extra: Option<&str>) -> EmbedFooter {
+    let text = match extra {
+        Some(extra) => format!("Requested by {} - {}", requester.name, extra),
+        None => format!("Requested by {}", requester.name),
+    };
+
+    let footer = EmbedFooterBuilder::new(text);
+
+    match ImageSource::url(&requester.avatar_url) {
+        Ok(avatar) => footer.icon_url(avatar).build(),
+        Err(_) => footer.build(),
+    }
+}
+
+/// The now playing embed, the position is shown as a progress bar when it's known
+fn now_playing_embed(item: &PlaylistItem, loop_mode: LoopMode, position: Option<Duration>) -> Embed {
+    let loop_text = format!("Loop: {}", loop_mode.value());
+    let footer = match &item.requester {
+        Some(requester) => requester_footer(requester, Some(&loop_text)),
+        None => EmbedFooterBuilder::new(loop_text).build(),
+    };
+
+    let mut description = match &item.stream_title {
+        // Radios show the song on air with the station below
+        Some(stream_title) => format!("[{}]({})\n{}", stream_title, &item.original_url, &item.title),
+        None => format!("[{}]({})", &item.title, &item.original_url),
+    };
+    if let Some(requester) = &item.requester {
+        description.push_str(&format!("\nAdded <t:{}:R>", requester.requested_at));
+    }
+    if let Some(position) = position {
+        description.push_str(&format!("\n\n{}", format_progress(position, item.duration)));
+    }
 
-pub async fn send_now_playing(
-    http: &twilight_http::Client,
-    channel_id: Id<ChannelMarker>,
-    item: &PlaylistItem
-) {
     let embed = EmbedBuilder::new()
         .title(":musical_note:  **Now playing**")
-        .description(format!("[{}]({})", &item.title, &item.original_url))
-        .thumbnail(ImageSource::url(&item.thumbnail.clone().unwrap_or("".into())).unwrap())
+        .description(description)
         .color(Colour::GOLD.0)
-        .build();
+        .footer(footer);
 
-    let _ = http
+    with_thumbnail(embed, item).build()
+}
+
+/// Post the now playing panel for the guild or edit it in place if there is one already
+pub async fn update_now_playing(
+    state: &StateRef,
+    guild_id: Id<GuildMarker>,
+    channel_id: Id<ChannelMarker>,
+    item: &PlaylistItem,
+    loop_mode: LoopMode,
+    paused: bool
+) {
+    let embeds = [now_playing_embed(item, loop_mode, None)];
+    let components = player_buttons(loop_mode, paused);
+
+    let current = state.now_playing_messages.read().await.get(&guild_id).copied();
+
+    if let Some((panel_channel_id, message_id)) = current {
+        if panel_channel_id == channel_id {
+            let update = state.http
+                .update_message(channel_id, message_id)
+                .embeds(Some(&embeds))
+                .and_then(|message| message.components(Some(&components)));
+
+            if let Ok(update) = update {
+                if update.await.is_ok() {
+                    return
+                }
+            }
+        }
+    }
+
+    // There is no panel or it was deleted, post a new one
+    let message = state.http
         .create_message(channel_id)
-        .embeds(&[
-            embed
-        ]).unwrap()
-        .await;
+        .embeds(&embeds)
+        .and_then(|message| message.components(&components));
+
+    if let Ok(message) = message {
+        if let Ok(response) = message.await {
+            if let Ok(message) = response.model().await {
+                state.now_playing_messages.write().await.insert(guild_id, (channel_id, message.id));
+            }
+        }
+    }
+}
+
+/// Edit the now playing panel of the guild to show the current state, used after pausing, looping, etc.
+pub async fn refresh_now_playing(state: &StateRef, guild_id: Id<GuildMarker>) {
+    let current = state.now_playing_messages.read().await.get(&guild_id).copied();
+    let channel_id = match current {
+        Some((channel_id, _)) => channel_id,
+        None => return,
+    };
+
+    let item = state.system_playlist.now_playing(&guild_id);
+    let loop_mode = state.system_playlist.loop_mode(&guild_id);
+
+    if let Some(item) = item {
+        let paused = is_paused(state, guild_id).await;
+        update_now_playing(state, guild_id, channel_id, &item, loop_mode, paused).await;
+    }
+}
+
+/// Remove the buttons of the now playing panel of the guild and forget it
+pub async fn close_now_playing(state: &StateRef, guild_id: Id<GuildMarker>) {
+    let current = state.now_playing_messages.write().await.remove(&guild_id);
+
+    if let Some((channel_id, message_id)) = current {
+        if let Ok(update) = state.http.update_message(channel_id, message_id).components(Some(&[])) {
+            let _ = update.await;
+        }
+    }
 }
 
+fn player_buttons(loop_mode: LoopMode, paused: bool) -> Vec<Component> {
+    vec![
+        Component::ActionRow(ActionRow {
+            components: vec![
+                if paused {
+                    button("player:pause", "Resume", ButtonStyle::Success, false)
+                } else {
+                    button("player:pause", "Pause", ButtonStyle::Secondary, false)
+                },
+                button("player:skip", "Skip", ButtonStyle::Primary, false),
+                button("player:stop", "Stop", ButtonStyle::Danger, false),
+                button("player:loop", &format!("Loop: {}", loop_mode.value()), ButtonStyle::Secondary, false),
+                button("player:shuffle", "Shuffle", ButtonStyle::Secondary, false),
+            ],
+        })
+    ]
+}
+
+/// Handles the buttons of the now playing panel, `args` is the action of the button
+pub async fn player_component(state: Arc<StateRef>, interaction: Interaction, args: String) -> Result<()> {
+    let guild_id = match interaction.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    // Only the users listening with the bot can use the panel
+    let call = match check_same_channel(&state, guild_id, interaction.author_id().unwrap()).await {
+        Ok(call) => call,
+        Err(message) => {
+            send_response(&state.http, interaction.application_id, interaction.id, &interaction.token, message).await?;
+            return Ok(())
+        },
+    };
+
+    // Acknowledge the button, the panel is edited in place below
+    state.http
+        .interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &InteractionResponse {
+            kind: InteractionResponseType::DeferredUpdateMessage,
+            data: None,
+        })
+        .await?;
+
+    match args.as_str() {
+        "pause" => {
+            if let Some(handle) = current_track(&state, guild_id).await {
+                let _ = if is_paused(&state, guild_id).await { handle.play() } else { handle.pause() };
+            }
+        },
+        "skip" => {
+            let interaction_channel_id = interaction.channel.clone().unwrap().id;
+            let author_id = interaction.author_id().unwrap();
+            let roles = interaction.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default();
+
+            // When the song is skipped the panel is already updated with the next song, show the tally otherwise
+            if let Ok(vote @ SkipVote::Pending { .. }) = vote_skip(&state, guild_id, interaction_channel_id, author_id, &roles, &call).await {
+                state.http
+                    .interaction(interaction.application_id)
+                    .create_followup(&interaction.token)
+                    .embeds(&[vote.embed()])?
+                    .await?;
+            }
+
+            return Ok(())
+        },
+        "stop" => {
+            leave_voice(&state, guild_id).await;
+            return Ok(())
+        },
+        "loop" => {
+            let loop_mode = state.system_playlist.loop_mode(&guild_id).next();
+            state.system_playlist.set_loop_mode(&guild_id, loop_mode);
+        },
+        "shuffle" => {
+            state.system_playlist.shuffle(&guild_id);
+        },
+        _ => {},
+    }
+
+    refresh_now_playing(&state, guild_id).await;
+
+    Ok(())
+}
 
+pub async fn is_paused(state: &StateRef, guild_id: Id<GuildMarker>) -> bool {
+    match current_track(state, guild_id).await {
+        Some(handle) => matches!(handle.get_info().await.map(|info| info.playing), Ok(PlayMode::Pause)),
+        None => false,
+    }
+}
 
 pub async fn send_queue_finished(
     http: &twilight_http::Client,
@@ -648,13 +2318,12 @@ pub async fn send_queue_finished(
         .await;
 }
 
-pub async fn send_now_playing_on_end(slf: &TrackEndNotifier, item: &PlaylistItem) {
+pub async fn send_cannot_play_on_end(slf: &TrackEndNotifier, item: &PlaylistItem, err: &anyhow::Error) {
     let embed = EmbedBuilder::new()
-        .title(":musical_note:  **Now playing**")
-        .description(format!("[{}]({})", &item.title, &item.original_url))
-        .thumbnail(ImageSource::url(&item.thumbnail.clone().unwrap_or("".into())).unwrap())
-        .color(Colour::GOLD.0)
-        .build();
+        .title(":musical_note:  **Cannot play**")
+        .description(format!("[{}]({})\n{}", &item.title, &item.original_url, err))
+        .color(Colour::RED.0);
+    let embed = with_thumbnail(embed, item).build();
 
     let _ = slf.state.http
         .create_message(slf.channel_id)
@@ -664,18 +2333,40 @@ pub async fn send_now_playing_on_end(slf: &TrackEndNotifier, item: &PlaylistItem
         .await;
 }
 
-pub async fn send_cannot_play_on_end(slf: &TrackEndNotifier, item: &PlaylistItem) {
-    let embed = EmbedBuilder::new()
-        .title(":musical_note:  **Cannot play**")
-        .description(format!("[{}]({})", &item.title, &item.original_url))
-        .thumbnail(ImageSource::url(&item.thumbnail.clone().unwrap_or("".into())).unwrap())
-        .color(Colour::RED.0)
-        .build();
+#[cfg(test)]
+mod test {
+    use twilight_model::id::Id;
 
-    let _ = slf.state.http
-        .create_message(slf.channel_id)
-        .embeds(&[
-            embed
-        ]).unwrap()
-        .await;
-}
\ No newline at end of file
+    use super::{now_playing_embed, song_added_embed};
+    use crate::backend;
+    use crate::pot::{LoopMode, PlaylistItem, Requester};
+
+    #[test]
+    fn embeds_without_thumbnail() {
+        let requester = Requester::new(Id::new(1), "user".to_owned(), String::new());
+        let mut item = PlaylistItem::new("never gonna", "never gonna", "https://www.youtube.com/results?search_query=never+gonna", "search", backend::YT_DLP);
+        assert!(item.thumbnail.is_none());
+
+        assert!(song_added_embed(&requester, &item).thumbnail.is_none());
+        assert!(now_playing_embed(&item, LoopMode::Off, None).thumbnail.is_none());
+
+        item.thumbnail = Some("https://i.ytimg.com/vi/dQw4w9WgXcQ/default.jpg".to_owned());
+        let embed = song_added_embed(&requester, &item);
+        assert_eq!(embed.thumbnail.map(|thumbnail| thumbnail.url), item.thumbnail);
+    }
+
+    #[test]
+    fn radio_embeds() {
+        let requester = Requester::new(Id::new(1), "user".to_owned(), String::new());
+        let mut item = PlaylistItem::new("https://radio.example.com/stream", "Example FM", "https://radio.example.com/stream", backend::HTTP, backend::HTTP);
+        item.is_live = Some(true);
+        item.stream_title = Some("Artist - Song".to_owned());
+        item.requester = Some(requester.clone());
+
+        assert!(song_added_embed(&requester, &item).thumbnail.is_none());
+
+        let embed = now_playing_embed(&item, LoopMode::Off, None);
+        assert!(embed.thumbnail.is_none());
+        assert!(embed.description.unwrap().starts_with("[Artist - Song](https://radio.example.com/stream)\nExample FM"));
+    }
+}
@@ -0,0 +1,171 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::Mutex;
+use twilight_model::id::{marker::GuildMarker, Id};
+
+use crate::StateRef;
+use super::commands::{current_track, format_duration, is_paused, leave_voice_keeping_queue, listeners, refresh_now_playing, send_message};
+
+/// How often the voice channels are checked for inactivity
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Why the bot is not doing anything useful in a guild
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Inactivity {
+    Alone,
+    Paused,
+    Idle
+}
+
+#[derive(Debug, Default)]
+struct GuildActivity {
+    inactive: Option<(Inactivity, Instant)>,
+    paused_alone: bool
+}
+
+/// Keeps track of the guilds where the bot is in a voice channel and since when it's inactive there
+#[derive(Debug, Default)]
+pub struct IdleTracker {
+    guilds: Mutex<HashMap<Id<GuildMarker>, GuildActivity>>
+}
+
+impl IdleTracker {
+    /// Start watching a guild after joining a voice channel
+    pub async fn watch(&self, guild_id: Id<GuildMarker>) {
+        self.guilds.lock().await.insert(guild_id, GuildActivity::default());
+    }
+
+    async fn forget(&self, guild_id: Id<GuildMarker>) {
+        self.guilds.lock().await.remove(&guild_id);
+    }
+
+    /// Record the current inactivity of the guild and returns since when the bot has been inactive for that reason
+    async fn mark(&self, guild_id: Id<GuildMarker>, inactivity: Option<Inactivity>) -> Option<Instant> {
+        let mut guilds = self.guilds.lock().await;
+        let activity = guilds.entry(guild_id).or_default();
+
+        match (inactivity, activity.inactive) {
+            (Some(inactivity), Some((previous, since))) if inactivity == previous => Some(since),
+            (Some(inactivity), _) => {
+                let since = Instant::now();
+                activity.inactive = Some((inactivity, since));
+                Some(since)
+            },
+            (None, _) => {
+                activity.inactive = None;
+                None
+            },
+        }
+    }
+
+    async fn set_paused_alone(&self, guild_id: Id<GuildMarker>, paused_alone: bool) -> bool {
+        let mut guilds = self.guilds.lock().await;
+        let activity = guilds.entry(guild_id).or_default();
+        std::mem::replace(&mut activity.paused_alone, paused_alone)
+    }
+}
+
+/// Seconds read from an env var, falling back to a default
+fn env_duration(key: &str, default_secs: u64) -> Duration {
+    let secs = std::env::var(key).ok().and_then(|value| value.parse::<u64>().ok()).unwrap_or(default_secs);
+    Duration::from_secs(secs)
+}
+
+async fn announce(state: &StateRef, guild_id: Id<GuildMarker>, message: &str) {
+    let text_channel = state.system_playlist.channels(&guild_id).map(|(_, text_channel)| text_channel);
+
+    if let Some(text_channel) = text_channel {
+        let _ = send_message(&state.http, text_channel, message).await;
+    }
+}
+
+/// Pause when everyone left the bot voice channel and resume when somebody comes back
+pub async fn voice_state_update(state: Arc<StateRef>, guild_id: Id<GuildMarker>) {
+    let call = match state.songbird.get(guild_id) {
+        Some(call) => call,
+        None => return,
+    };
+
+    let bot_channel = call.lock().await.current_channel();
+    let alone = match bot_channel {
+        Some(bot_channel) => listeners(&state, bot_channel).is_empty(),
+        None => return,
+    };
+
+    let track = match current_track(&state, guild_id).await {
+        Some(track) => track,
+        None => return,
+    };
+
+    if alone {
+        if !is_paused(&state, guild_id).await && track.pause().is_ok() {
+            state.idle.set_paused_alone(guild_id, true).await;
+
+            let alone_timeout = env_duration("ALONE_TIMEOUT", 120);
+            announce(&state, guild_id, &format!("Everyone left, paused. Leaving in {} if nobody comes back", format_duration(Some(alone_timeout.as_secs() as f32)))).await;
+            refresh_now_playing(&state, guild_id).await;
+        }
+    } else if state.idle.set_paused_alone(guild_id, false).await && track.play().is_ok() {
+        // Only resume what was paused because the channel was empty
+        announce(&state, guild_id, "Welcome back, resuming").await;
+        refresh_now_playing(&state, guild_id).await;
+    }
+}
+
+/// Leave the voice channels where the bot is alone, paused or idle for too long
+pub async fn idle_watcher(state: Arc<StateRef>) {
+    // Leave after being alone in the voice channel for this long
+    let alone_timeout = env_duration("ALONE_TIMEOUT", 120);
+    // Leave after being paused or not playing anything for this long
+    let idle_timeout = env_duration("IDLE_TIMEOUT", 300);
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let guilds: Vec<Id<GuildMarker>> = state.idle.guilds.lock().await.keys().copied().collect();
+
+        for guild_id in guilds {
+            let call = match state.songbird.get(guild_id) {
+                Some(call) => call,
+                None => {
+                    // The bot left the voice channel
+                    state.idle.forget(guild_id).await;
+                    continue
+                },
+            };
+
+            let bot_channel = call.lock().await.current_channel();
+
+            let inactivity = if bot_channel.map(|bot_channel| listeners(&state, bot_channel).is_empty()).unwrap_or(true) {
+                Some(Inactivity::Alone)
+            } else if current_track(&state, guild_id).await.is_none() {
+                Some(Inactivity::Idle)
+            } else if is_paused(&state, guild_id).await {
+                Some(Inactivity::Paused)
+            } else {
+                None
+            };
+
+            let since = state.idle.mark(guild_id, inactivity).await;
+
+            if let (Some(inactivity), Some(since)) = (inactivity, since) {
+                // Guilds can change the idle timeout in their settings
+                let guild_idle_timeout = state.settings.read().await.get(&guild_id).idle_timeout.map(Duration::from_secs).unwrap_or(idle_timeout);
+
+                let (timeout, message) = match inactivity {
+                    Inactivity::Alone => (alone_timeout, "Nobody is listening, left voice channel"),
+                    Inactivity::Paused => (guild_idle_timeout, "Paused for too long, left voice channel"),
+                    Inactivity::Idle => (guild_idle_timeout, "Nothing to play, left voice channel"),
+                };
+
+                if since.elapsed() >= timeout {
+                    announce(&state, guild_id, message).await;
+                    // The queue is kept for when the bot is back
+                    leave_voice_keeping_queue(&state, guild_id).await;
+                    state.idle.forget(guild_id).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+
+use crate::backend::{self, AUDIO_EXTENSIONS};
+use crate::helpers;
+use crate::pot::PlaylistItem;
+
+const INDEX_PATH: &str = "data/library.json";
+
+/// The directory is walked again when the index is older than this
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An audio file of the library with the tags read from it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LibraryTrack {
+    /// Path relative to the library directory
+    pub path: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Seconds
+    pub duration: Option<f32>,
+    /// Size and modification time in seconds, the tags are read again when they change
+    pub size: u64,
+    pub modified: u64
+}
+
+impl LibraryTrack {
+    /// `Artist - Title` or only the title when the file has no artist
+    pub fn display_title(&self) -> String {
+        match &self.artist {
+            Some(artist) => format!("{} - {}", artist, self.title),
+            None => self.title.to_owned(),
+        }
+    }
+
+    /// Item played by the local backend, straight from the file
+    pub fn playlist_item(&self) -> PlaylistItem {
+        let mut item = PlaylistItem::new(&self.path, &self.display_title(), &format!("file:///{}", &self.path), backend::LOCAL, backend::LOCAL);
+        item.duration = self.duration;
+        item
+    }
+
+    /// Every word of the query is in the title, artist, album or path
+    fn matches(&self, words: &[String]) -> bool {
+        let haystack = format!(
+            "{} {} {} {}",
+            &self.title,
+            self.artist.as_deref().unwrap_or_default(),
+            self.album.as_deref().unwrap_or_default(),
+            &self.path
+        ).to_lowercase();
+
+        words.iter().all(|word| haystack.contains(word.as_str()))
+    }
+}
+
+/// Audio files in `LOCAL_MEDIA_PATH`, indexed in `data/library.json` so the tags
+/// are only read from new or changed files
+#[derive(Debug)]
+pub struct Library {
+    root: PathBuf,
+    index_path: PathBuf,
+    tracks: RwLock<Vec<LibraryTrack>>,
+    last_scan: Mutex<Option<Instant>>
+}
+
+impl Library {
+    /// Library of the directory with the tracks of the saved index
+    pub fn load(root: impl Into<PathBuf>, index_path: impl Into<PathBuf>) -> Self {
+        let index_path = index_path.into();
+
+        let tracks = fs::read_to_string(&index_path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+
+        Self {
+            root: root.into(),
+            index_path,
+            tracks: RwLock::new(tracks),
+            last_scan: Mutex::new(None)
+        }
+    }
+
+    /// The same directory the local backend plays from
+    pub fn from_env() -> Self {
+        let root = std::env::var("LOCAL_MEDIA_PATH").unwrap_or_else(|_| "data/media".to_owned());
+        Self::load(root, INDEX_PATH)
+    }
+
+    /// Walk the directory and update the index, returns how many tracks there are.
+    /// Reads the disk so it's better called with `spawn_blocking`
+    pub fn rescan(&self) -> anyhow::Result<usize> {
+        *self.last_scan.lock().unwrap() = Some(Instant::now());
+
+        let known = self.tracks();
+        let mut tracks = Vec::new();
+
+        for path in audio_files(&self.root) {
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let relative = match path.strip_prefix(&self.root) {
+                Ok(relative) => relative.to_string_lossy().replace('\\', "/"),
+                Err(_) => continue,
+            };
+            let size = metadata.len();
+            let modified = metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs())
+                .unwrap_or(0);
+
+            // Unchanged files keep the tags read the last time
+            match known.iter().find(|track| track.path == relative && track.size == size && track.modified == modified) {
+                Some(track) => tracks.push(track.clone()),
+                None => tracks.push(read_track(&path, relative, size, modified)),
+            }
+        }
+
+        tracks.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let count = tracks.len();
+        // Most scans find nothing new, the index is only written when a file was added, changed or removed
+        if tracks != known {
+            helpers::write_json(&self.index_path.to_string_lossy(), serde_json::to_string(&tracks)?)?;
+            *self.tracks.write().unwrap() = tracks;
+        }
+
+        Ok(count)
+    }
+
+    /// Rescan when the index is old, new files show up without restarting the bot
+    pub async fn refresh(self: &Arc<Self>) {
+        // Marked as scanned right away so commands sent during the scan don't start another one
+        {
+            let mut last_scan = self.last_scan.lock().unwrap();
+            if last_scan.map(|last_scan| last_scan.elapsed() <= RESCAN_INTERVAL).unwrap_or(false) { return }
+            *last_scan = Some(Instant::now());
+        }
+
+        let library = self.clone();
+        match tokio::task::spawn_blocking(move || library.rescan()).await {
+            Ok(Err(err)) => tracing::warn!("cannot scan the library: {}", err),
+            Err(err) => tracing::warn!("library scan panicked: {}", err),
+            Ok(Ok(_)) => {},
+        }
+    }
+
+    pub fn tracks(&self) -> Vec<LibraryTrack> {
+        self.tracks.read().unwrap().clone()
+    }
+
+    /// Tracks that have every word of the query, ignoring case
+    pub fn search(&self, query: &str) -> Vec<LibraryTrack> {
+        let words: Vec<String> = query.split_whitespace().map(|word| word.to_lowercase()).collect();
+        if words.is_empty() { return Vec::new() }
+
+        self.tracks.read().unwrap()
+            .iter()
+            .filter(|track| track.matches(&words))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Files with an audio extension in the directory and its subdirectories
+fn audio_files(root: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() && has_audio_extension(&path) {
+                files.push(path);
+            }
+        }
+    }
+
+    files
+}
+
+fn has_audio_extension(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .map(|extension| AUDIO_EXTENSIONS.contains(&extension.as_str()))
+        .unwrap_or(false)
+}
+
+/// Track of the file with the tags symphonia finds, the file name is the title of files without tags
+fn read_track(path: &Path, relative: String, size: u64, modified: u64) -> LibraryTrack {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_else(|| relative.to_owned());
+
+    let mut track = LibraryTrack {
+        path: relative,
+        title: stem,
+        artist: None,
+        album: None,
+        duration: None,
+        size,
+        modified
+    };
+
+    if let Err(err) = read_tags(path, &mut track) {
+        tracing::warn!("cannot read the tags of {}: {}", path.display(), err);
+    }
+
+    track
+}
+
+fn read_tags(path: &Path, track: &mut LibraryTrack) -> anyhow::Result<()> {
+    let stream = MediaSourceStream::new(Box::new(fs::File::open(path)?), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let mut probed = symphonia::default::get_probe().format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())?;
+
+    // Tags can be before the container, like ID3 in mp3 files, or inside of it
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|metadata| metadata.current()) {
+        apply_tags(revision, track);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        apply_tags(revision, track);
+    }
+
+    if let Some(params) = probed.format.default_track().map(|default_track| &default_track.codec_params) {
+        if let (Some(frames), Some(sample_rate)) = (params.n_frames, params.sample_rate) {
+            track.duration = Some(frames as f32 / sample_rate as f32);
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_tags(revision: &MetadataRevision, track: &mut LibraryTrack) {
+    for tag in revision.tags() {
+        let value = tag.value.to_string().trim().to_owned();
+        if value.is_empty() { continue }
+
+        match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => track.title = value,
+            Some(StandardTagKey::Artist) => track.artist = Some(value),
+            Some(StandardTagKey::Album) => track.album = Some(value),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::Library;
+    use crate::backend::LOCAL;
+    use crate::test_utils::temp_dir;
+
+    /// One second of silence as 8 kHz 8 bit mono wav
+    fn silent_wav() -> Vec<u8> {
+        let samples = 8000u32;
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + samples).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(8u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(samples.to_le_bytes());
+        wav.extend(vec![128u8; samples as usize]);
+        wav
+    }
+
+    #[test]
+    fn scan_and_search() {
+        let dir = temp_dir("library");
+        let root = dir.join("media");
+        fs::create_dir_all(root.join("clips")).unwrap();
+        fs::write(root.join("clips/Air Horn.wav"), silent_wav()).unwrap();
+        fs::write(root.join("Applause.wav"), silent_wav()).unwrap();
+        fs::write(root.join("notes.txt"), "not audio").unwrap();
+
+        let library = Library::load(&root, dir.join("library.json"));
+        assert_eq!(library.rescan().unwrap(), 2);
+
+        let found = library.search("air HORN");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, "clips/Air Horn.wav");
+        assert_eq!(found[0].title, "Air Horn");
+        assert!((found[0].duration.unwrap() - 1.0).abs() < 0.01);
+
+        assert!(library.search("clips applause").is_empty());
+        assert!(library.search("").is_empty());
+
+        let item = found[0].playlist_item();
+        assert_eq!(item.original_url, "file:///clips/Air Horn.wav");
+        assert_eq!(item.backend.as_deref(), Some(LOCAL));
+
+        // The saved index is loaded again without scanning
+        let reloaded = Library::load(&root, dir.join("library.json"));
+        assert_eq!(reloaded.tracks(), library.tracks());
+
+        // Nothing changed so the index isn't written again
+        fs::remove_file(dir.join("library.json")).unwrap();
+        assert_eq!(library.rescan().unwrap(), 2);
+        assert!(!dir.join("library.json").exists());
+
+        fs::write(root.join("Drum Roll.wav"), silent_wav()).unwrap();
+        assert_eq!(library.rescan().unwrap(), 3);
+        assert_eq!(Library::load(&root, dir.join("library.json")).tracks().len(), 3);
+    }
+}
@@ -8,7 +8,7 @@ use songbird::{
     tracks::{TrackHandle},
     Songbird,
 };
-use std::{collections::HashMap, env, error::Error, sync::Arc};
+use std::{collections::HashMap, env, error::Error, sync::Arc, time::Duration};
 use tokio::sync::RwLock;
 use twilight_gateway::{
     stream::{self, ShardEventStream},
@@ -18,22 +18,38 @@ use twilight_gateway::{
 };
 use twilight_http::Client as HttpClient;
 use twilight_model::{
-    id::{marker::{GuildMarker, ApplicationMarker, UserMarker}, Id},
+    id::{marker::{GuildMarker, ApplicationMarker, UserMarker, ChannelMarker, MessageMarker}, Id},
 };
 use twilight_cache_inmemory::InMemoryCache;
 use twilight_standby::Standby;
+use interaction::IdleTracker;
+use settings::SettingsStore;
+use library::Library;
 
 mod interaction;
 mod helpers;
 mod yt;
+mod ytdlp;
+mod backend;
+mod radio;
+mod library;
 mod pot;
 mod colour;
+mod cache;
+mod settings;
+mod spotify;
+#[cfg(test)]
+mod test_utils;
 
 #[derive(Debug)]
 pub struct StateRef {
     http: HttpClient,
     trackdata: RwLock<HashMap<Id<GuildMarker>, TrackHandle>>,
-    system_playlist: Arc<RwLock<SystemPlaylist>>,
+    now_playing_messages: RwLock<HashMap<Id<GuildMarker>, (Id<ChannelMarker>, Id<MessageMarker>)>>,
+    idle: IdleTracker,
+    settings: RwLock<SettingsStore>,
+    system_playlist: Arc<SystemPlaylist>,
+    library: Arc<Library>,
     songbird: Arc<Songbird>,
     standby: Standby,
     application_id: Id<ApplicationMarker>,
@@ -99,7 +115,9 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     tracing_subscriber::fmt::init();
 
     std::env::var("DISCORD_TOKEN").expect("Missing DISCORD_TOKEN env var");
-    std::env::var("YOUTUBE_TOKEN").expect("Missing YOUTUBE_TOKEN env var");
+    if yt::YoutubeAPI::from_env().is_none() {
+        tracing::warn!("no YOUTUBE_TOKEN, youtube links are resolved with yt-dlp and /search is disabled");
+    }
 
     // Setup dir structure
     match helpers::setup_system() {
@@ -109,6 +127,9 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         },
     }
 
+    // Remove interrupted downloads and keep the media cache under its size limit
+    cache::MediaCache::global().cleanup();
+
     let (mut shards, state) = {
         let token = env::var("DISCORD_TOKEN")?;
 
@@ -146,14 +167,18 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
             .build();
 
         let songbird = Songbird::twilight(Arc::new(senders), user_id);
-        let system_playlist = Arc::new(RwLock::new(SystemPlaylist::new()));
+        let system_playlist = Arc::new(SystemPlaylist::load());
 
         (
             shards,
             Arc::new(StateRef {
                 http,
                 trackdata: Default::default(),
+                now_playing_messages: Default::default(),
+                idle: Default::default(),
+                settings: RwLock::new(SettingsStore::load()),
                 system_playlist: system_playlist.clone(),
+                library: Arc::new(Library::from_env()),
                 songbird: Arc::new(songbird),
                 standby: Standby::new(),
                 application_id,
@@ -163,6 +188,18 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         )
     };
 
+    // Ask to resume the queues saved before the restart
+    tokio::spawn(interaction::offer_resume(state.clone()));
+
+    // Index the local library in the background, it can take a while the first time
+    {
+        let library = state.library.clone();
+        tokio::spawn(async move { library.refresh().await });
+    }
+
+    // Leave the voice channels where nobody is listening
+    tokio::spawn(interaction::idle_watcher(state.clone()));
+
     // {
     //     let (updated, deleted) = update_global_commands(state.clone()).await?;
     //     println!("Updated {updated} global commands and deleted {deleted} global commands.");
@@ -175,7 +212,15 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
 
     let mut stream = ShardEventStream::new(shards.iter_mut());
     loop {
-        let event = match stream.next().await {
+        let next = tokio::select! {
+            next = stream.next() => next,
+            _ = tokio::signal::ctrl_c() => {
+                println!("Shutting down");
+                break;
+            },
+        };
+
+        let event = match next {
             Some((_, Ok(event))) => event,
             Some((_, Err(source))) => {
                 tracing::warn!(?source, "error receiving event");
@@ -189,7 +234,7 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
             None => break,
         };
 
-        state.standby.process(&event);
+        let standby_results = state.standby.process(&event);
         state.songbird.process(&event).await;
 
         match &event {
@@ -199,7 +244,9 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
                 }
             },
             Event::InteractionCreate(interaction) => {
-                let handler = interaction::handle_interaction(interaction.clone(), state.clone()).await;
+                // Components that a command waits for with standby are answered by that command
+                let awaited = standby_results.matched() > 0;
+                let handler = interaction::handle_interaction(interaction.clone(), state.clone(), awaited).await;
                 if let Err(err) = handler {
                     eprintln!(
                         "Error found on interaction {}\nError: {:?}",
@@ -211,7 +258,19 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         }
 
         state.cache.update(&event);
+
+        // Checked after updating the cache so the voice states are up to date
+        if let Event::VoiceStateUpdate(voice_state) = &event {
+            if let Some(guild_id) = voice_state.0.guild_id {
+                tokio::spawn(interaction::voice_state_update(state.clone(), guild_id));
+            }
+        }
     }
+
+    // Don't leave yt-dlp processes running after the bot exits
+    state.system_playlist.cancel_all();
+    state.system_playlist.flush();
+    tokio::time::sleep(Duration::from_millis(500)).await;
     
     Ok(())
 }
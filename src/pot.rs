@@ -1,62 +1,179 @@
 use anyhow::{anyhow};
-use serde::{Deserialize};
+use serde::{Deserialize, Serialize};
+use twilight_interactions::command::{CommandOption, CreateOption};
 use twilight_model::id::Id;
-use twilight_model::id::marker::GuildMarker;
-use std::collections::HashMap;
+use twilight_model::id::marker::{GuildMarker, ChannelMarker, UserMarker};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{BufReader, BufRead};
-use std::path::Path;
-use std::process::ChildStdout;
+use std::path::PathBuf;
 use std::{
-    io::{Read},
-    process::{Command, Stdio},
+    sync::{Arc, Mutex, RwLock, atomic::{AtomicU64, Ordering}},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use crate::backend::{self, BackendRegistry, Media};
+use crate::helpers;
+use crate::settings::GuildSettings;
+use crate::spotify::{SpotifyAPI, SpotifyTrack, SpotifyTracks};
+use crate::yt::{YoutubeAPI, YoutubeError, YoutubePlaylistItemsResponse, YoutubePlaylistItemsResult, YoutubeVideo};
+use crate::ytdlp::{CancelToken, Canceller};
 
-#[cfg(not(feature = "tokio-02-marker"))]
-use tokio::{task};
-#[cfg(feature = "tokio-02-marker")]
-use tokio_compat::{task};
 
-use crate::helpers;
-use crate::yt::YoutubeResult;
+/// Directory where every guild queue is saved as `<guild_id>.json`
+const QUEUES_PATH: &str = "data/queues";
+
+/// Changes to a queue within this time are written to disk together
+const PERSIST_DELAY: Duration = Duration::from_secs(2);
+
+/// Youtube urls resolved with yt-dlp because the data api couldn't be used
+static YOUTUBE_FALLBACKS: AtomicU64 = AtomicU64::new(0);
+
+/// How many youtube urls were resolved with yt-dlp since the bot started
+pub fn youtube_fallbacks () -> u64 {
+    YOUTUBE_FALLBACKS.load(Ordering::Relaxed)
+}
+
+/// Queues of every guild, each one behind its own lock so a guild never waits for another one
+#[derive(Debug)]
+pub struct SystemPlaylist {
+    guilds: RwLock<HashMap<Id<GuildMarker>, Arc<Mutex<GuildQueue>>>>,
+    backends: BackendRegistry,
+    /// Shared so the access token is requested once and reused
+    spotify: Option<SpotifyAPI>,
+    writer: Arc<QueueWriter>
+}
+
+/// Writes the guild queues to `<dir>/<guild_id>.json` off the async runtime,
+/// a burst of changes to a queue is written once with the latest state
+#[derive(Debug)]
+struct QueueWriter {
+    dir: PathBuf,
+    /// Json of the queues that changed since they were last written
+    pending: Mutex<HashMap<Id<GuildMarker>, String>>,
+    /// Writes are done one at a time so an older queue never replaces a newer one
+    writing: Mutex<()>
+}
+
+impl QueueWriter {
+    fn new (dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            pending: Mutex::new(HashMap::new()),
+            writing: Mutex::new(())
+        }
+    }
+
+    /// Save the queue after `PERSIST_DELAY`, right away when there is no runtime to wait on
+    fn schedule (self: &Arc<Self>, guild_id: Id<GuildMarker>, json: String) {
+        // A write is already waiting, it will take the new json
+        if self.pending.lock().unwrap().insert(guild_id, json).is_some() { return }
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let writer = self.clone();
+                runtime.spawn(async move {
+                    tokio::time::sleep(PERSIST_DELAY).await;
+                    let _ = tokio::task::spawn_blocking(move || writer.write(guild_id)).await;
+                });
+            },
+            Err(_) => self.write(guild_id),
+        }
+    }
+
+    fn write (&self, guild_id: Id<GuildMarker>) {
+        let _writing = self.writing.lock().unwrap();
+
+        let json = match self.pending.lock().unwrap().remove(&guild_id) {
+            Some(json) => json,
+            None => return,
+        };
+
+        let path = self.dir.join(format!("{}.json", guild_id));
+        if let Err(err) = helpers::write_json(&path.to_string_lossy(), json) {
+            tracing::warn!("cannot persist queue {}: {:?}", path.display(), err);
+        }
+    }
+
+    /// Write every queue that is waiting, used before shutting down
+    fn flush (&self) {
+        let guild_ids: Vec<Id<GuildMarker>> = self.pending.lock().unwrap().keys().copied().collect();
+
+        for guild_id in guild_ids {
+            self.write(guild_id);
+        }
+    }
+}
 
+/// Queue and playing status of a single guild, this is what gets written to disk
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GuildQueue {
+    items: Vec<PlaylistItem>,
+    now_playing: Option<PlaylistItem>,
+    playing: bool,
+    voice_channel: Option<Id<ChannelMarker>>,
+    text_channel: Option<Id<ChannelMarker>>,
+    #[serde(default)]
+    loop_mode: LoopMode,
+    #[serde(default)]
+    volume: Option<f32>,
+    /// Users that voted to skip the current item
+    #[serde(skip)]
+    skip_votes: HashSet<Id<UserMarker>>,
+    /// A prefetch task is resolving the next items
+    #[serde(skip)]
+    prefetching: bool,
+    /// Stops the yt-dlp processes working for the guild
+    #[serde(skip)]
+    canceller: Canceller
+}
 
-#[derive(Debug, Deserialize, Clone, Copy)]
-pub enum YOUTUBE_DL_BACKEND {
-    YT_DLP,
-    YOUTUBE_DL
+/// What happens with the current item when it ends
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, CommandOption, CreateOption)]
+pub enum LoopMode {
+    #[default]
+    #[option(name = "Off", value = "off")]
+    Off,
+    #[option(name = "Track", value = "track")]
+    Track,
+    #[option(name = "Queue", value = "queue")]
+    Queue
 }
 
-impl YOUTUBE_DL_BACKEND {
+impl LoopMode {
     pub fn value (&self) -> &str {
         match self {
-            YOUTUBE_DL_BACKEND::YT_DLP => "yt-dlp",
-            YOUTUBE_DL_BACKEND::YOUTUBE_DL => "youtube-dl",
+            LoopMode::Off => "off",
+            LoopMode::Track => "track",
+            LoopMode::Queue => "queue",
         }
     }
-}
 
-#[derive(Debug)]
-pub struct SystemPlaylist {
-    guilds_playlists: HashMap<Id<GuildMarker>, Vec<PlaylistItem>>,
-    guilds_playing: HashMap<Id<GuildMarker>, bool>
+    /// The mode after this one, used to cycle the modes with a single button
+    pub fn next (&self) -> Self {
+        match self {
+            LoopMode::Off => LoopMode::Track,
+            LoopMode::Track => LoopMode::Queue,
+            LoopMode::Queue => LoopMode::Off,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum PotPlayInputType {
     Url(url::Url),
     SpotifyUrl(url::Url),
-    Search(String)
+    Search(String),
+    /// Items that are ready to be queued, like the tracks of the local library
+    Items(Vec<PlaylistItem>)
 }
 
 impl PotPlayInputType {
     fn is_url(&self) -> bool {
-        matches!(*self, Self::Url(_))
+        matches!(*self, Self::Url(_) | Self::SpotifyUrl(_) | Self::Items(_))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 enum YoutubeUrlType {
     Video(String),
     Playlist(String),
@@ -64,35 +181,97 @@ enum YoutubeUrlType {
     None
 }
 
+/// Hosts of youtube links without `www.`
+const YOUTUBE_HOSTS: [&str; 5] = ["youtube.com", "m.youtube.com", "music.youtube.com", "youtube-nocookie.com", "youtu.be"];
+
 fn youtube_url_extractor (url: &url::Url) -> YoutubeUrlType {
-    match url.host_str() {
-        Some(url_str) => {
-            let path_segments = url
-                    .path_segments()
-                    .map(|c| c.collect::<Vec<_>>()).unwrap_or_default();
+    let host = match url.host_str() {
+        Some(host) => host.trim_start_matches("www.").to_lowercase(),
+        None => return YoutubeUrlType::None,
+    };
 
-            if url_str.ends_with("youtube.com") || url_str.ends_with("youtu.be") {
-                let query = query_pairs_to_hashmap(url);
+    if !YOUTUBE_HOSTS.contains(&host.as_str()) {
+        return YoutubeUrlType::None
+    }
 
-                if query.contains_key("list") {
-                    YoutubeUrlType::Playlist(query.get("list").unwrap().to_owned())
-                } else if query.contains_key("v") {
-                    YoutubeUrlType::Video(query.get("v").unwrap().to_owned())
-                } else if path_segments[0] == "shorts" {
-                    YoutubeUrlType::Short(path_segments[1].to_string())
-                } else {
-                    YoutubeUrlType::None
-                }
-            } else {
-                YoutubeUrlType::None
-            }
-        },
+    let query = query_pairs_to_hashmap(url);
+    let path_segments: Vec<&str> = url
+        .path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default();
+
+    // A video in a playlist adds the whole playlist
+    if let Some(list) = query.get("list").filter(|list| is_youtube_id(list)) {
+        return YoutubeUrlType::Playlist(list.to_owned())
+    }
+
+    let video_id = if host == "youtu.be" {
+        path_segments.first().copied()
+    } else {
+        match path_segments.as_slice() {
+            ["shorts", short_id, ..] if is_youtube_id(short_id) => return YoutubeUrlType::Short(short_id.to_string()),
+            ["live" | "embed" | "v" | "e", video_id, ..] => Some(*video_id),
+            _ => query.get("v").map(|video_id| video_id.as_str()),
+        }
+    };
+
+    match video_id.filter(|video_id| is_youtube_id(video_id)) {
+        Some(video_id) => YoutubeUrlType::Video(video_id.to_owned()),
         None => YoutubeUrlType::None,
     }
 }
 
+fn is_youtube_id (id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Seconds of the `t` or `start` timestamp of a youtube url, also read from the fragment like `#t=30`
+fn youtube_start_time (url: &url::Url) -> Option<f32> {
+    let query = query_pairs_to_hashmap(url);
+    let fragment = url.fragment().and_then(|fragment| fragment.strip_prefix("t="));
+
+    query.get("t")
+        .or_else(|| query.get("start"))
+        .map(|value| value.as_str())
+        .or(fragment)
+        .and_then(parse_timestamp)
+}
+
+/// Timestamps like `90`, `90s`, `1m30s` or `1h2m3s`, None for invalid ones and for 0
+fn parse_timestamp (value: &str) -> Option<f32> {
+    let value = value.trim();
+
+    if value.chars().all(|c| c.is_ascii_digit()) {
+        return value.parse::<f32>().ok().filter(|seconds| *seconds > 0.0)
+    }
+
+    let mut seconds = 0.0;
+    let mut number = String::new();
+
+    for c in value.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue
+            },
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return None,
+        };
+
+        seconds += number.parse::<f32>().ok()? * unit;
+        number.clear();
+    }
+
+    if !number.is_empty() { return None }
+
+    Some(seconds).filter(|seconds| *seconds > 0.0)
+}
+
 enum SpotifyUrlType {
     Track(String),
+    Album(String),
     Playlist(String),
     None
 }
@@ -101,14 +280,17 @@ fn spotify_url_extractor (url: &url::Url) -> SpotifyUrlType {
     match url.host_str() {
         Some(url_str) => {
             if url_str.ends_with("open.spotify.com") {
+                // Localized urls start with a segment like `intl-es`
                 let path_segments = url
                     .path_segments()
-                    .map(|c| c.collect::<Vec<_>>()).unwrap_or_default();
+                    .map(|c| c.filter(|segment| !segment.starts_with("intl-")).collect::<Vec<_>>()).unwrap_or_default();
 
                 if path_segments.len() < 2 { return SpotifyUrlType::None }
 
                 if path_segments[0] == "playlist" {
                     SpotifyUrlType::Playlist(path_segments[1].to_owned())
+                } else if path_segments[0] == "album" {
+                    SpotifyUrlType::Album(path_segments[1].to_owned())
                 } else if path_segments[0] == "track" {
                     SpotifyUrlType::Track(path_segments[1].to_owned())
                 } else {
@@ -122,6 +304,86 @@ fn spotify_url_extractor (url: &url::Url) -> SpotifyUrlType {
     }
 }
 
+/// Item for a text search, the title is the query until it's resolved
+fn search_playlist_item (query: &str) -> PlaylistItem {
+    let search_url = url::Url::parse_with_params("https://www.youtube.com/results", &[("search_query", query)])
+        .map(|url| url.to_string())
+        .unwrap_or_else(|_| "https://www.youtube.com/results".to_string());
+
+    PlaylistItem {
+        id: query.to_string(),
+        title: query.to_string(),
+        original_url: search_url,
+        extractor: "search".to_string(),
+        thumbnail: None,
+        duration: None,
+        playlist_id: None,
+        webpage_url: None,
+        is_live: None,
+        was_live: None,
+        stream_title: None,
+        start_time: None,
+        backend: Some(backend::YT_DLP.to_string()),
+        requester: None,
+        unresolved: Some(Unresolved::Search { query: query.to_string() })
+    }
+}
+
+/// Most songs added from a youtube playlist or a spotify album or playlist, from `YOUTUBE_PLAYLIST_MAX_ITEMS`
+fn playlist_max_items () -> usize {
+    std::env::var("YOUTUBE_PLAYLIST_MAX_ITEMS").ok().and_then(|value| value.parse::<usize>().ok()).unwrap_or(500)
+}
+
+/// How many of the next items are resolved before their turn, from `PREFETCH_AHEAD`
+fn prefetch_ahead () -> usize {
+    std::env::var("PREFETCH_AHEAD").ok().and_then(|value| value.parse::<usize>().ok()).unwrap_or(2)
+}
+
+/// Resolve the next unresolved items of the guild queue in the background so they are ready when their turn comes,
+/// the playlist lock is not held while searching
+pub async fn prefetch (playlist: Arc<SystemPlaylist>, guild_id: Id<GuildMarker>) {
+    let pending = match playlist.start_prefetch(&guild_id, prefetch_ahead()) {
+        Some(pending) => pending,
+        None => return,
+    };
+
+    let mut cancel = playlist.cancel_token(&guild_id);
+
+    for item in pending {
+        match playlist.resolve(&item, &mut cancel).await {
+            Ok(resolved) => playlist.replace_resolved(&guild_id, &item, resolved),
+            Err(err) => tracing::debug!("cannot prefetch {}: {:?}", item.title, err),
+        }
+    }
+
+    playlist.finish_prefetch(&guild_id);
+}
+
+/// Spotify tracks can't be played directly, they are searched on youtube when it's their turn
+fn spotify_tracks_to_playlist_items (tracks: Vec<SpotifyTrack>) -> Vec<PlaylistItem> {
+    tracks.into_iter().filter_map(|track| {
+        let id = track.id.clone()?;
+
+        Some(PlaylistItem {
+            original_url: track.external_urls.spotify.clone().unwrap_or_else(|| format!("https://open.spotify.com/track/{}", &id)),
+            id,
+            title: track.search_query(),
+            extractor: "spotify".to_string(),
+            thumbnail: track.thumbnail(),
+            duration: Some(track.duration_ms as f32 / 1000.0),
+            playlist_id: None,
+            webpage_url: None,
+            is_live: None,
+            was_live: None,
+            stream_title: None,
+            start_time: None,
+            backend: Some(backend::YT_DLP.to_string()),
+            requester: None,
+            unresolved: Some(Unresolved::Spotify { query: track.search_query() })
+        })
+    }).collect()
+}
+
 fn query_pairs_to_hashmap (url: &url::Url) -> HashMap<String, String> {
     let mut map: HashMap<String, String> = HashMap::new();
     for (key, value) in url.query_pairs() {
@@ -132,388 +394,693 @@ fn query_pairs_to_hashmap (url: &url::Url) -> HashMap<String, String> {
     map
 }
 
-fn youtube_result_to_playlist_items (yt_result: YoutubeResult) -> Vec<PlaylistItem> {
-    if let YoutubeResult::Ok(response) = yt_result {
-        response.items.into_iter().filter_map(|item| {
-            if let Some(resource_id) = item.snippet.resourceId {
-                Some(PlaylistItem {
-                    original_url: format!("https://www.youtube.com/watch?v={}", &resource_id.videoId),
-                    id: resource_id.videoId,
-                    title: item.snippet.title,
-                    extractor: "youtube".to_string(),
-                    thumbnail: item.snippet.thumbnails.get("default").map(|t| t.url.to_owned()),
-                    duration: None,
-                    playlist_id: None,
-                    webpage_url: None,
-                    is_live: None,
-                    was_live: None,
-                    backend: Some(YOUTUBE_DL_BACKEND::YT_DLP)
-                })
-            } else {
-                None
+fn youtube_response_to_playlist_items (response: YoutubePlaylistItemsResponse) -> Vec<PlaylistItem> {
+    response.items.into_iter().filter_map(youtube_item_to_playlist_item).collect()
+}
+
+fn youtube_item_to_playlist_item (item: YoutubePlaylistItemsResult) -> Option<PlaylistItem> {
+    let resource_id = item.snippet.resourceId?;
+
+    Some(PlaylistItem {
+        original_url: format!("https://www.youtube.com/watch?v={}", &resource_id.videoId),
+        id: resource_id.videoId,
+        title: item.snippet.title,
+        extractor: "youtube".to_string(),
+        thumbnail: item.snippet.thumbnails.get("default").map(|t| t.url.to_owned()),
+        duration: None,
+        playlist_id: None,
+        webpage_url: None,
+        is_live: None,
+        was_live: None,
+        stream_title: None,
+        start_time: None,
+        backend: Some(backend::YT_DLP.to_string()),
+        requester: None,
+        unresolved: None
+    })
+}
+
+/// Items of a youtube video, short or playlist from the data api
+async fn fetch_youtube (api: &YoutubeAPI, url_type: YoutubeUrlType) -> anyhow::Result<FetchedItems> {
+    match url_type {
+        YoutubeUrlType::Playlist(playlist_id) => {
+            let mut response = api.playlist(&playlist_id, playlist_max_items()).await?;
+            let warning = response.warning.take();
+            if let Some(warning) = &warning {
+                tracing::info!("playlist {}: {}", &playlist_id, warning);
             }
-        }).collect()
+
+            Ok(FetchedItems {
+                items: add_video_details(api, youtube_response_to_playlist_items(response)).await,
+                warning
+            })
+        },
+        YoutubeUrlType::Video(video_id) | YoutubeUrlType::Short(video_id) => {
+            let items = youtube_response_to_playlist_items(api.video(&video_id).await?);
+            let found = !items.is_empty();
+            let items = add_video_details(api, items).await;
+
+            if found && items.is_empty() {
+                return Err(anyhow!("This video is private, deleted or not available in the country of the bot"))
+            }
+            Ok(items.into())
+        },
+        YoutubeUrlType::None => Err(anyhow!("Not a youtube url")),
+    }
+}
+
+/// Items of an input, with the reason when fewer songs than the input has were loaded
+#[derive(Debug, Default)]
+pub struct FetchedItems {
+    pub items: Vec<PlaylistItem>,
+    /// Like a playlist cut at the item cap, it's shown to the user with the added songs
+    pub warning: Option<String>
+}
+
+impl From<Vec<PlaylistItem>> for FetchedItems {
+    fn from(items: Vec<PlaylistItem>) -> Self {
+        Self { items, warning: None }
+    }
+}
+
+impl From<SpotifyTracks> for FetchedItems {
+    fn from(spotify_tracks: SpotifyTracks) -> Self {
+        Self { items: spotify_tracks_to_playlist_items(spotify_tracks.tracks), warning: spotify_tracks.warning }
+    }
+}
+
+/// A search result offered to the user before adding it to the queue
+#[derive(Debug, Clone)]
+pub struct SearchChoice {
+    pub item: PlaylistItem,
+    pub channel: Option<String>
+}
+
+/// The first `count` youtube videos for the query with their duration and channel
+pub async fn search_choices (query: &str, count: u32) -> anyhow::Result<Vec<SearchChoice>> {
+    let api = YoutubeAPI::from_env().ok_or_else(|| anyhow!("Youtube search is not configured"))?;
+
+    let response = api.search(query, count).await.map_err(|error| anyhow!("Youtube search failed: {}", error))?;
+
+    let mut choices: Vec<SearchChoice> = response.items
+        .into_iter()
+        .filter_map(|item| {
+            let channel = item.snippet.channelTitle.clone();
+            youtube_item_to_playlist_item(item).map(|item| SearchChoice { item, channel })
+        })
+        .collect();
+
+    // The search doesn't say how long the videos are
+    let ids: Vec<String> = choices.iter().map(|choice| choice.item.id.to_owned()).collect();
+    if let Ok(videos) = api.videos(&ids).await {
+        let region = youtube_region();
+        choices.retain_mut(|choice| apply_video_details(&videos, region.as_deref(), &mut choice.item));
+    }
+
+    Ok(choices)
+}
+
+/// Fill the duration and live status of youtube items and leave out the videos that can't be played,
+/// the items are kept as they are when youtube doesn't answer
+async fn add_video_details (api: &YoutubeAPI, mut items: Vec<PlaylistItem>) -> Vec<PlaylistItem> {
+    let ids: Vec<String> = items.iter().map(|item| item.id.to_owned()).collect();
+
+    if let Ok(videos) = api.videos(&ids).await {
+        let region = youtube_region();
+        items.retain_mut(|item| apply_video_details(&videos, region.as_deref(), item));
+    }
+
+    items
+}
+
+/// Copy the details of the video to the item, false when the video is private, deleted or blocked
+fn apply_video_details (videos: &HashMap<String, YoutubeVideo>, region: Option<&str>, item: &mut PlaylistItem) -> bool {
+    let video = match videos.get(&item.id) {
+        Some(video) if video.is_playable_in(region) => video,
+        _ => return false,
+    };
+
+    item.duration = video.duration();
+    item.is_live = Some(video.is_live());
+    item.was_live = Some(video.was_live());
+
+    true
+}
+
+/// Country of the bot from `YOUTUBE_REGION`, videos blocked there are not added
+fn youtube_region () -> Option<String> {
+    std::env::var("YOUTUBE_REGION").ok().filter(|region| !region.trim().is_empty()).map(|region| region.trim().to_uppercase())
+}
+
+/// Error out when a 0 based index is not inside a queue of `len` items, the message uses 1 based positions
+fn check_index (index: usize, len: usize) -> anyhow::Result<()> {
+    if len == 0 {
+        Err(anyhow!("The queue is empty"))
+    } else if index >= len {
+        Err(anyhow!("Position {} is out of range, the queue has {} songs", index + 1, len))
     } else {
-        Vec::new()
+        Ok(())
+    }
+}
+
+/// Fisher-Yates shuffle with a xorshift generator seeded by the std random hasher, no need for a rng crate
+fn shuffle_items<T> (items: &mut [T]) {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut seed = RandomState::new().build_hasher().finish() | 1;
+
+    for i in (1..items.len()).rev() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+
+        let j = (seed % (i as u64 + 1)) as usize;
+        items.swap(i, j);
     }
 }
 
 impl SystemPlaylist {
     pub fn new () -> Self {
         Self {
-            guilds_playlists: HashMap::new(),
-            guilds_playing: HashMap::new()
+            guilds: RwLock::new(HashMap::new()),
+            backends: BackendRegistry::from_env(),
+            spotify: SpotifyAPI::from_env(),
+            writer: Arc::new(QueueWriter::new(QUEUES_PATH))
         }
     }
 
-    pub fn set_status (&mut self, guild_id: &Id<GuildMarker>, is_playing: bool) {
-        if self.guilds_playing.contains_key(guild_id) {
-            let guild_playlist_status = self.guilds_playing.get_mut(guild_id).unwrap();
-            *guild_playlist_status = is_playing;
+    /// Load the guild queues saved in `data/queues`, items that were playing are put back at the head of the queue
+    pub fn load () -> Self {
+        let mut guilds = HashMap::new();
+
+        let entries = match fs::read_dir(QUEUES_PATH) {
+            Ok(entries) => entries,
+            Err(_) => return Self::new(),
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            let guild_id = match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()).and_then(Id::new_checked) {
+                Some(guild_id) => guild_id,
+                None => continue,
+            };
+
+            let guild_queue = fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| serde_json::from_str::<GuildQueue>(&json).map_err(anyhow::Error::from));
+
+            match guild_queue {
+                Ok(mut guild_queue) => {
+                    // The bot was restarted so nothing is playing now, the interrupted item will be played again
+                    if let Some(item) = guild_queue.now_playing.take() {
+                        guild_queue.items.insert(0, item);
+                    }
+                    guild_queue.playing = false;
+
+                    guilds.insert(guild_id, Arc::new(Mutex::new(guild_queue)));
+                },
+                Err(err) => tracing::warn!("cannot load queue {}: {:?}", path.display(), err),
+            }
+        }
 
-            println!("status set :{}", guild_playlist_status);
-        } else {
-            self.guilds_playing.insert(guild_id.to_owned(), is_playing);
-            println!("status set :{}", is_playing);
+        Self {
+            guilds: RwLock::new(guilds),
+            backends: BackendRegistry::from_env(),
+            spotify: SpotifyAPI::from_env(),
+            writer: Arc::new(QueueWriter::new(QUEUES_PATH))
         }
     }
 
-    pub fn is_playing (&self, guild_id: &Id<GuildMarker>) -> bool {
-        if self.guilds_playing.contains_key(guild_id) {
-            *self.guilds_playing.get(guild_id).unwrap()
-        } else {
-            false
+    /// Returns the queue of the guild, creating an empty one if the guild has none
+    fn guild (&self, guild_id: &Id<GuildMarker>) -> Arc<Mutex<GuildQueue>> {
+        if let Some(guild_queue) = self.guilds.read().unwrap().get(guild_id) {
+            return guild_queue.clone()
         }
+
+        self.guilds.write().unwrap().entry(*guild_id).or_default().clone()
+    }
+
+    /// Run `f` with the guild queue locked, only the guild is locked and never across an await
+    fn read<T> (&self, guild_id: &Id<GuildMarker>, f: impl FnOnce(&GuildQueue) -> T) -> T {
+        let guild_queue = self.guild(guild_id);
+        let guild_queue = guild_queue.lock().unwrap();
+        f(&guild_queue)
     }
 
-    /// Consumes and return a item from the the guild playlist removing the item
-    pub fn consume(&mut self, guild_id: &Id<GuildMarker>) -> Option<PlaylistItem> {
-        if self.guilds_playlists.contains_key(guild_id) { // Guild playlist already exist
-            let guild_playlist = self.guilds_playlists.get_mut(guild_id).unwrap();
-            
-            if guild_playlist.is_empty() {
+    /// Like `read` but the queue can be changed, it's written to disk in the background afterwards
+    fn update<T> (&self, guild_id: &Id<GuildMarker>, f: impl FnOnce(&mut GuildQueue) -> T) -> T {
+        let guild_queue = self.guild(guild_id);
+        let (result, json) = {
+            let mut guild_queue = guild_queue.lock().unwrap();
+            let result = f(&mut guild_queue);
+            (result, serde_json::to_string(&*guild_queue))
+        };
+
+        match json {
+            Ok(json) => self.writer.schedule(*guild_id, json),
+            Err(err) => tracing::warn!("cannot serialize the queue of {}: {:?}", guild_id, err),
+        }
+
+        result
+    }
+
+    /// Write the queues with changes that are not saved yet
+    pub fn flush (&self) {
+        self.writer.flush();
+    }
+
+    /// Returns the guilds that have a saved queue and know where to resume it
+    pub fn resumable (&self) -> Vec<(Id<GuildMarker>, Id<ChannelMarker>, Id<ChannelMarker>, usize)> {
+        let guilds: Vec<(Id<GuildMarker>, Arc<Mutex<GuildQueue>>)> = self.guilds.read().unwrap()
+            .iter()
+            .map(|(guild_id, guild_queue)| (*guild_id, guild_queue.clone()))
+            .collect();
+
+        guilds.into_iter().filter_map(|(guild_id, guild_queue)| {
+            let guild_queue = guild_queue.lock().unwrap();
+
+            if guild_queue.playing || guild_queue.items.is_empty() {
+                return None
+            }
+
+            match (guild_queue.voice_channel, guild_queue.text_channel) {
+                (Some(voice_channel), Some(text_channel)) => Some((guild_id, voice_channel, text_channel, guild_queue.items.len())),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    /// Remember the voice channel the bot is in and the text channel where it sends messages
+    pub fn set_channels (&self, guild_id: &Id<GuildMarker>, voice_channel: Id<ChannelMarker>, text_channel: Id<ChannelMarker>) {
+        self.update(guild_id, |guild_queue| {
+            guild_queue.voice_channel = Some(voice_channel);
+            guild_queue.text_channel = Some(text_channel);
+        });
+    }
+
+    /// Returns the voice and text channel where the guild queue was last played
+    pub fn channels (&self, guild_id: &Id<GuildMarker>) -> Option<(Id<ChannelMarker>, Id<ChannelMarker>)> {
+        self.read(guild_id, |guild_queue| Some((guild_queue.voice_channel?, guild_queue.text_channel?)))
+    }
+
+    pub fn set_status (&self, guild_id: &Id<GuildMarker>, is_playing: bool) {
+        self.update(guild_id, |guild_queue| {
+            guild_queue.playing = is_playing;
+
+            if !is_playing {
+                // Nothing is playing anymore so there is no current item
+                guild_queue.now_playing = None;
+            }
+        });
+    }
+
+    /// Stop playing but keep the queue, the current item goes back to its head to be played again
+    pub fn suspend (&self, guild_id: &Id<GuildMarker>) {
+        self.update(guild_id, |guild_queue| {
+            if let Some(item) = guild_queue.now_playing.take() {
+                guild_queue.items.insert(0, item);
+            }
+            guild_queue.playing = false;
+        });
+    }
+
+    /// Mark the guild as playing, false when it already was so only one caller starts the queue
+    pub fn try_start (&self, guild_id: &Id<GuildMarker>) -> bool {
+        self.update(guild_id, |guild_queue| !std::mem::replace(&mut guild_queue.playing, true))
+    }
+
+    pub fn is_playing (&self, guild_id: &Id<GuildMarker>) -> bool {
+        self.read(guild_id, |guild_queue| guild_queue.playing)
+    }
+
+    /// Consumes and return the next item of the guild playlist following the loop mode,
+    /// `track_ended` is false when the current item is being skipped
+    pub fn consume(&self, guild_id: &Id<GuildMarker>, track_ended: bool) -> Option<PlaylistItem> {
+        self.update(guild_id, |guild_queue| {
+            // The votes were for the item that is being replaced
+            guild_queue.skip_votes.clear();
+
+            if let Some(previous) = guild_queue.now_playing.take() {
+                match guild_queue.loop_mode {
+                    // Play the same item again, skipping it moves to the next one
+                    LoopMode::Track if track_ended => guild_queue.items.insert(0, previous),
+                    // Send the item to the tail of the queue
+                    LoopMode::Queue => guild_queue.items.push(previous),
+                    _ => {}
+                }
+            }
+
+            if guild_queue.items.is_empty() {
                 None
             } else {
-                Some(guild_playlist.remove(0))
+                let item = guild_queue.items.remove(0);
+                guild_queue.now_playing = Some(item.clone());
+                Some(item)
             }
-        } else { // The guild playlist is not currently in the system
-            None
-        }
+        })
+    }
+
+    /// Count a vote to skip the current item and returns how many of the `listeners` voted
+    pub fn add_skip_vote (&self, guild_id: &Id<GuildMarker>, user_id: Id<UserMarker>, listeners: &[Id<UserMarker>]) -> usize {
+        let guild_queue = self.guild(guild_id);
+        let mut guild_queue = guild_queue.lock().unwrap();
+        guild_queue.skip_votes.insert(user_id);
+
+        // Users that left the voice channel don't count
+        guild_queue.skip_votes.iter().filter(|voter| listeners.contains(voter)).count()
+    }
+
+    pub fn set_loop_mode (&self, guild_id: &Id<GuildMarker>, loop_mode: LoopMode) {
+        self.update(guild_id, |guild_queue| guild_queue.loop_mode = loop_mode);
+    }
+
+    /// Set the volume of the guild, 1.0 is the original volume and None goes back to the guild default volume
+    pub fn set_volume (&self, guild_id: &Id<GuildMarker>, volume: Option<f32>) {
+        self.update(guild_id, |guild_queue| guild_queue.volume = volume);
     }
 
-    /// Try to fetch a playlist or a single media item and add it to the guild playlist
-    pub async fn add(&mut self, guild_id: &Id<GuildMarker>, input: PotPlayInputType) -> anyhow::Result<(usize, &[PlaylistItem])> {
-        use crate::yt::YoutubeAPI;
+    pub fn volume (&self, guild_id: &Id<GuildMarker>) -> Option<f32> {
+        self.read(guild_id, |guild_queue| guild_queue.volume)
+    }
+
+    pub fn loop_mode (&self, guild_id: &Id<GuildMarker>) -> LoopMode {
+        self.read(guild_id, |guild_queue| guild_queue.loop_mode)
+    }
+
+    /// Returns the item that is currently playing in the guild
+    pub fn now_playing(&self, guild_id: &Id<GuildMarker>) -> Option<PlaylistItem> {
+        self.read(guild_id, |guild_queue| guild_queue.now_playing.clone())
+    }
 
-        // Load youtube token
-        let token = std::env::var("YOUTUBE_TOKEN").expect("missing YOUTUBE_TOKEN");
+    /// Show the song on air of the radio that is playing, returns false if the radio is not playing anymore
+    pub fn set_stream_title(&self, guild_id: &Id<GuildMarker>, url: &str, title: &str) -> bool {
+        self.update(guild_id, |guild_queue| {
+            match guild_queue.now_playing.as_mut() {
+                Some(item) if item.original_url == url => {
+                    item.stream_title = Some(title.to_owned());
+                    true
+                },
+                _ => false,
+            }
+        })
+    }
 
-        // Initialize Youtube api
-        let api = YoutubeAPI::new(&token);
+    /// Returns the upcoming items of the guild playlist, empty if the guild has no playlist
+    pub fn queue(&self, guild_id: &Id<GuildMarker>) -> Vec<PlaylistItem> {
+        self.read(guild_id, |guild_queue| guild_queue.items.clone())
+    }
 
+    /// Try to fetch a playlist or a single media item and add it to the guild playlist, returns the added items.
+    /// The guild queue is only locked to add the fetched items
+    pub async fn add(&self, guild_id: &Id<GuildMarker>, input: PotPlayInputType, requester: Requester, settings: &GuildSettings) -> anyhow::Result<FetchedItems> {
         // Check if the input is a url or a query
         let is_url = input.is_url();
 
         // Get a PlaylistItem vec
+        let FetchedItems { items: mut new_playlist_items, warning } = self.fetch(input).await?;
+
+        let playlist_items_len = new_playlist_items.len();
+        if playlist_items_len == 0 { return Err(anyhow!("No items in playlist")) }
+
+        for item in new_playlist_items.iter_mut() {
+            item.requester = Some(requester.clone());
+        }
+
+        // Leave out the songs longer than the guild limit, the duration is not always known
+        if let Some(max_duration) = settings.max_song_duration {
+            new_playlist_items.retain(|item| settings.is_duration_allowed(item.duration));
+            if new_playlist_items.is_empty() {
+                return Err(anyhow!("Songs longer than {}:{:02} can't be added", max_duration / 60, max_duration % 60))
+            }
+        }
+
+        // And the songs from sites the guild doesn't allow, searches are checked once they are resolved
+        if !settings.allowed_hosts.is_empty() {
+            new_playlist_items.retain(|item| item.unresolved.is_some() || settings.is_url_allowed(&item.original_url));
+            if new_playlist_items.is_empty() {
+                return Err(anyhow!("Songs from this site are not allowed here, allowed sites: {}", settings.allowed_hosts.join(", ")))
+            }
+        }
+
+        self.update(guild_id, |guild_queue| {
+            let guild_playlist = &mut guild_queue.items;
 
-        let playlist_result = match input {
+            // Only add what fits in the queue
+            if let Some(max_queue_length) = settings.max_queue_length {
+                let free = max_queue_length.saturating_sub(guild_playlist.len());
+                if free == 0 {
+                    return Err(anyhow!("The queue is full, the limit is {} songs", max_queue_length))
+                }
+                new_playlist_items.truncate(free);
+            }
+
+            // And what fits in the songs the user can have in the queue
+            if let Some(max_songs_per_user) = settings.max_songs_per_user {
+                let user_songs = guild_playlist.iter().filter(|item| item.is_requested_by(requester.id)).count();
+                let free = max_songs_per_user.saturating_sub(user_songs);
+                if free == 0 {
+                    return Err(anyhow!("You already have {} songs in the queue, that's the limit", max_songs_per_user))
+                }
+                new_playlist_items.truncate(free);
+            }
+
+            if !is_url {
+                // If the input was not an url we just keep the first item
+                new_playlist_items.truncate(1);
+            }
+
+            guild_playlist.extend(new_playlist_items.iter().cloned());
+
+            Ok(FetchedItems { items: new_playlist_items, warning })
+        })
+    }
+
+    /// Get the items of the input from youtube, spotify or the backend of the url
+    async fn fetch(&self, input: PotPlayInputType) -> anyhow::Result<FetchedItems> {
+        match input {
             PotPlayInputType::Url(url) => {
                 // Check if the url is a youtube url
-                match youtube_url_extractor (&url) {
-                    YoutubeUrlType::Playlist(playlist_id) => Ok(youtube_result_to_playlist_items(api.playlist(&playlist_id).await)),
-                    YoutubeUrlType::Video(video_id) => Ok(youtube_result_to_playlist_items(api.video(&video_id).await)),
-                    YoutubeUrlType::Short(short_id) => Ok(youtube_result_to_playlist_items(api.video(&short_id).await)),
-                    YoutubeUrlType::None => Self::get_playlist(url.as_str(), YOUTUBE_DL_BACKEND::YT_DLP).await,
+                let url_type = youtube_url_extractor (&url);
+                let start_time = match &url_type {
+                    YoutubeUrlType::Video(_) | YoutubeUrlType::Short(_) => youtube_start_time(&url),
+                    _ => None,
+                };
+
+                let mut fetched = match (url_type, YoutubeAPI::from_env()) {
+                    (YoutubeUrlType::None, _) => self.backends.route_probed(&url).await.resolve(url.as_str(), &mut CancelToken::never()).await.map(FetchedItems::from),
+                    (_, None) => self.youtube_fallback(&url, "there is no YOUTUBE_TOKEN").await,
+                    (url_type, Some(api)) => match fetch_youtube(&api, url_type).await {
+                        Err(err) if err.downcast_ref::<YoutubeError>().map(|err| err.is_key_unusable()).unwrap_or(false) => {
+                            self.youtube_fallback(&url, &err.to_string()).await
+                        },
+                        result => result,
+                    },
+                }?;
+
+                // Start where the link says unless it's past the end
+                if let (Some(start_time), [item]) = (start_time, fetched.items.as_mut_slice()) {
+                    if item.duration.map(|duration| start_time < duration).unwrap_or(true) {
+                        item.start_time = Some(start_time);
+                    }
                 }
+
+                Ok(fetched)
             },
             PotPlayInputType::SpotifyUrl(url) => {
-                match spotify_url_extractor(&url) {
-                    _ => Self::get_playlist(url.as_str(), YOUTUBE_DL_BACKEND::YOUTUBE_DL).await,
+                match (spotify_url_extractor(&url), &self.spotify) {
+                    (SpotifyUrlType::None, _) => Err(anyhow!("Unsupported spotify url")),
+                    (_, None) => Err(anyhow!("Spotify is not configured")),
+                    (SpotifyUrlType::Track(track_id), Some(spotify)) => spotify.track(&track_id).await.map(|track| spotify_tracks_to_playlist_items(vec![track]).into()),
+                    (SpotifyUrlType::Album(album_id), Some(spotify)) => spotify.album(&album_id, playlist_max_items()).await.map(FetchedItems::from),
+                    (SpotifyUrlType::Playlist(playlist_id), Some(spotify)) => spotify.playlist(&playlist_id, playlist_max_items()).await.map(FetchedItems::from),
                 }
             },
             PotPlayInputType::Search(query) => {
-                // Search way
-                Self::get_playlist(&format!("ytsearch1:{}", query), YOUTUBE_DL_BACKEND::YT_DLP).await
+                // Searched when the item gets close to the head of the queue
+                Ok(vec![search_playlist_item(&query)].into())
             },
-        };
-
-        match playlist_result {
-            Ok(mut new_playlist_items) => {
-                let playlist_items_len = new_playlist_items.len();
-                if playlist_items_len == 0 { return Err(anyhow!("No items in playlist")) }
-
-                // Check if guilds playlists contains a playlist for the guild
-                if !self.guilds_playlists.contains_key(guild_id) {
-                    // Create a new empty list for guild
-                    self.guilds_playlists.insert(*guild_id, Vec::new());
-                }
+            PotPlayInputType::Items(items) => Ok(items.into()),
+        }
+    }
 
-                // Get a reference for the guild playlist
-                let guild_playlist = self.guilds_playlists.get_mut(&guild_id).unwrap();
+    /// Resolve a youtube url with yt-dlp when the data api can't be used
+    async fn youtube_fallback(&self, url: &url::Url, reason: &str) -> anyhow::Result<FetchedItems> {
+        let fallbacks = YOUTUBE_FALLBACKS.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::info!("resolving {} with yt-dlp because {}, {} fallbacks since the start", url, reason, fallbacks);
 
-                if is_url {
-                    // If the input type was an url we just append the new playlist items
-                    guild_playlist.append(&mut new_playlist_items);
+        let mut items = self.backends.route(url).resolve(url.as_str(), &mut CancelToken::never()).await?;
 
-                    let index = guild_playlist.len() - playlist_items_len;
-                    let slice = &guild_playlist[index..];
-                    Ok((playlist_items_len, slice))
-                } else {
-                    // If the input was not an url we just get the first item and push it to the guild playlist
-                    guild_playlist.push(new_playlist_items.remove(0));
+        let max_items = playlist_max_items();
+        let warning = (items.len() > max_items).then(|| format!("The playlist has more than {} songs, only the first {} were loaded", max_items, max_items));
+        items.truncate(max_items);
 
-                    let index = guild_playlist.len() - 1;
-                    let slice = &guild_playlist[index..];
-                    Ok((1, slice))
-                }
-            },
-            Err(err) => Err(err),
-        }
+        Ok(FetchedItems { items, warning })
     }
 
-    /// Remove all items from the playlist and returns true if the playlist is cleared of false if the guild has no playlist
-    pub fn clear(&mut self, guild_id: &Id<GuildMarker>) -> bool{
-        if self.guilds_playlists.contains_key(guild_id) { // Guild playlist already exist
-            let guild_playlist = self.guilds_playlists.get_mut(guild_id).unwrap();
-            guild_playlist.clear();
+    /// Remove all items from the playlist
+    pub fn clear(&self, guild_id: &Id<GuildMarker>) {
+        self.update(guild_id, |guild_queue| guild_queue.items.clear());
+    }
 
-            true
-        } else { // The guild playlist is not currently in the system
-            false
-        }
+    /// Shuffle the upcoming items of the guild playlist and returns how many items were shuffled
+    pub fn shuffle(&self, guild_id: &Id<GuildMarker>) -> usize {
+        self.update(guild_id, |guild_queue| {
+            shuffle_items(&mut guild_queue.items);
+            guild_queue.items.len()
+        })
     }
 
-    /// Fetch playlist with yt-dlp and parse the result
-    async fn get_playlist (url: &str, backend: YOUTUBE_DL_BACKEND) -> anyhow::Result<Vec<PlaylistItem>> {
-        let ytdl_args = [
-            "-j",
-            "-f",
-            "webm[abr>0]/bestaudio/best",
-            "-R",
-            "infinite",
-            "--yes-playlist",
-            "--ignore-config",
-            "--no-warnings",
-            url,
-            "-o",
-            "-",
-        ];
+    /// Remove the items from `start` to `end` (both included, 0 based) and returns them
+    pub fn remove(&self, guild_id: &Id<GuildMarker>, start: usize, end: usize) -> anyhow::Result<Vec<PlaylistItem>> {
+        self.update(guild_id, |guild_queue| {
+            check_index(start, guild_queue.items.len())?;
+            check_index(end, guild_queue.items.len())?;
+            if start > end { return Err(anyhow!("The start of the range is after the end")) }
 
-        let mut ytdlp_child = Command::new(backend.value())
-            .args(ytdl_args)
-            .stdin(Stdio::null())
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
+            Ok(guild_queue.items.drain(start..=end).collect())
+        })
+    }
 
-        // This rigmarole is required due to the inner synchronous reading context.
-        let stderr = ytdlp_child.stderr.take();
+    /// Remove every item added by the user from the queue and returns them
+    pub fn remove_requested_by(&self, guild_id: &Id<GuildMarker>, user_id: Id<UserMarker>) -> Vec<PlaylistItem> {
+        self.update(guild_id, |guild_queue| {
+            let (removed, kept) = std::mem::take(&mut guild_queue.items)
+                .into_iter()
+                .partition(|item| item.is_requested_by(user_id));
+            guild_queue.items = kept;
+            removed
+        })
+    }
 
-        let (_returned_stderr, value) = task::spawn_blocking(move || {
-            let mut child_stderr = stderr.unwrap();
+    /// Move the item at `from` to the position `to` (0 based) and returns a copy of the moved item
+    pub fn move_item(&self, guild_id: &Id<GuildMarker>, from: usize, to: usize) -> anyhow::Result<PlaylistItem> {
+        self.update(guild_id, |guild_queue| {
+            check_index(from, guild_queue.items.len())?;
+            check_index(to, guild_queue.items.len())?;
 
-            let mut output_data: String = String::new();
-            let _ = child_stderr.read_to_string(&mut output_data);
+            let item = guild_queue.items.remove(from);
+            guild_queue.items.insert(to, item.clone());
 
-            (child_stderr, output_data)
+            Ok(item)
         })
-        .await?;
+    }
 
-        let _ = ytdlp_child.wait();
+    /// Swap the items at `a` and `b` (0 based) and returns copies of both items in their new order
+    pub fn swap(&self, guild_id: &Id<GuildMarker>, a: usize, b: usize) -> anyhow::Result<(PlaylistItem, PlaylistItem)> {
+        self.update(guild_id, |guild_queue| {
+            check_index(a, guild_queue.items.len())?;
+            check_index(b, guild_queue.items.len())?;
 
-        let jsons: Vec<&str> = value.split('\n').collect();
+            guild_queue.items.swap(a, b);
 
-        let items: Vec<PlaylistItem> = jsons.iter().filter_map(|json_str| {
-            match serde_json::from_str::<PlaylistItem>(json_str) {
-                Ok(mut item) => {
-                    item.backend = Some(backend);
-                    Some(item)
-                },
-                Err(_) => None,
-            }
-        }).collect();
-
-        Ok(items)
-    }
-
-    pub async fn get_media (&self, item: &PlaylistItem) -> anyhow::Result<songbird::input::File<std::string::String>> {
-        let _ = helpers::graceful_mkdir("data/cache");
-        let fpath = format!("data/cache/media/{}/{}", item.extractor, item.id);
-        let path = Path::new(&fpath);
-
-        let file_path = if Self::check_file(path) {
-            println!("Loaded from cache");
-            Some(path.to_str().unwrap().to_string())
-        } else {
-            println!("Loaded from ytdl");
-            let path_str = path.to_str().unwrap();
-
-            Self::ytdlp_download(
-                path_str, 
-                &item.original_url, 
-                *item.backend
-                    .as_ref()
-                    .unwrap_or(&YOUTUBE_DL_BACKEND::YT_DLP)
-            ).await;
-    
-            if Self::check_file(path) {
-                Some(path_str.to_string())
-            } else {
-                None
-            }
+            Ok((guild_queue.items[a].clone(), guild_queue.items[b].clone()))
+        })
+    }
+
+    /// Find the youtube match of an item that was added without a playable url,
+    /// spotify items keep their title, thumbnail and duration, the requester is always kept
+    pub async fn resolve (&self, item: &PlaylistItem, cancel: &mut CancelToken) -> anyhow::Result<PlaylistItem> {
+        let query = match &item.unresolved {
+            Some(Unresolved::Spotify { query }) | Some(Unresolved::Search { query }) => query,
+            None => return Ok(item.clone()),
         };
 
-        match file_path {
-            Some(file_path) => {
-                let source = songbird::input::File::new(file_path);
-                Ok(source)
-            },
-            None => Err(anyhow!("No file path")),
+        let found = self.backends.default_backend().resolve(&format!("ytsearch1:{}", query), cancel).await?;
+        let found = found.into_iter().next().ok_or_else(|| anyhow!("No match found for {}", query))?;
+
+        match &item.unresolved {
+            Some(Unresolved::Spotify { .. }) => Ok(PlaylistItem {
+                id: found.id,
+                extractor: found.extractor,
+                original_url: found.original_url,
+                webpage_url: found.webpage_url,
+                duration: item.duration.or(found.duration),
+                backend: found.backend,
+                unresolved: None,
+                ..item.clone()
+            }),
+            _ => Ok(PlaylistItem {
+                requester: item.requester.clone(),
+                unresolved: None,
+                ..found
+            }),
         }
     }
 
-    // pub async fn get_media_stream(&self, item: &PlaylistItem) -> anyhow::Result<songbird::input::Input> {
-    //     let ytdlp_child = Self::ytdlp_stream(
-    //         &item.original_url,
-    //         *item.backend
-    //                 .as_ref()
-    //                 .unwrap_or(&YOUTUBE_DL_BACKEND::YT_DLP)
-    //     ).await?;
-    //     let input = Self::ffmpeg_to_input(ytdlp_child).await?;
-    //     Ok(input)
-    // }
-
-    pub async fn ytdlp_download(path_str: &str, item_original_url: &str, backend: YOUTUBE_DL_BACKEND) {
-        let ytdl_args = [
-            "--print-json",
-            "-f",
-            "webm[abr>0]/bestaudio/best",
-            "-R",
-            "infinite",
-            "--no-playlist",
-            "--ignore-config",
-            "--no-warnings",
-            item_original_url,
-            "-o",
-            path_str,
-        ];
+    /// Resolve the current item of the guild, the resolved item replaces it so it's searched only once.
+    /// The guild queue is not locked while searching
+    pub async fn resolve_now_playing (&self, guild_id: &Id<GuildMarker>) -> anyhow::Result<PlaylistItem> {
+        let item = self.now_playing(guild_id).ok_or_else(|| anyhow!("Nothing is playing"))?;
+        if item.unresolved.is_none() { return Ok(item) }
 
-        println!("{ytdl_args:?}");
-
-        let mut yt_dlp = Command::new(backend.value())
-            .args(ytdl_args)
-            .stdin(Stdio::null())
-            .stderr(Stdio::inherit())
-            .stdout(Stdio::null())
-            .spawn().expect("yt-dlp failed to execute");
-
-        let _ = yt_dlp.wait();
-    }
-
-    // Calls yt-dlp and gets the file data from stdout
-    pub async fn ytdlp_stream(item_original_url: &str, backend: YOUTUBE_DL_BACKEND) -> anyhow::Result<std::process::Child> {
-        let ytdl_args = [
-            "--print-json",
-            "-f",
-            "webm[abr>0]/bestaudio/best",
-            "-R",
-            "infinite",
-            "--no-playlist",
-            "--ignore-config",
-            "--no-warnings",
-            item_original_url,
-            "-o",
-            "-",
-        ];
+        let resolved = self.resolve(&item, &mut self.cancel_token(guild_id)).await?;
+
+        self.update(guild_id, |guild_queue| {
+            // Only if it didn't change while searching
+            if guild_queue.now_playing.as_ref().map(|current| current.is_same_unresolved(&item)).unwrap_or(false) {
+                guild_queue.now_playing = Some(resolved.clone());
+            }
+        });
 
-        // let log = fs::File::create("debug.txt").expect("failed to open log");
+        Ok(resolved)
+    }
 
-        let mut yt_dlp = Command::new(backend.value())
-            .args(ytdl_args)
-            .stdin(Stdio::null())
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn().expect("ytdlp failed to execute");
+    /// Unresolved items among the next `ahead` ones, None if the guild is already prefetching
+    fn start_prefetch (&self, guild_id: &Id<GuildMarker>, ahead: usize) -> Option<Vec<PlaylistItem>> {
+        let guild_queue = self.guild(guild_id);
+        let mut guild_queue = guild_queue.lock().unwrap();
+        if guild_queue.prefetching { return None }
 
-        // This rigmarole is required due to the inner synchronous reading context.
-        let stderr = yt_dlp.stderr.take();
-        let returned_stderr = task::spawn_blocking(move || {
-            let mut children_stderr = stderr.unwrap();
+        let pending: Vec<PlaylistItem> = guild_queue.items.iter().take(ahead).filter(|item| item.unresolved.is_some()).cloned().collect();
+        guild_queue.prefetching = !pending.is_empty();
 
-            let mut reader = BufReader::new(children_stderr.by_ref());
+        Some(pending)
+    }
 
-            let mut o_vec = vec![];
-            let _ = reader.read_until(0xA, &mut o_vec);
+    /// Put the resolved item in place of the unresolved one, if it's still in the queue
+    fn replace_resolved (&self, guild_id: &Id<GuildMarker>, unresolved: &PlaylistItem, resolved: PlaylistItem) {
+        self.update(guild_id, |guild_queue| {
+            let position = guild_queue.items.iter().position(|item| item.is_same_unresolved(unresolved));
 
-            children_stderr
-        })
-        .await?;
-
-        yt_dlp.stderr = Some(returned_stderr);
-
-        Ok(yt_dlp)
-    }
-
-    // pub async fn ffmpeg_to_input(mut input: std::process::Child) -> anyhow::Result<songbird::input::Input>{
-    //     let taken_stdout = input.stdout.take().ok_or_else(|| anyhow!("Failed to take children stdout"))?;
-
-    //     let ffmpeg_args = [
-    //         "-f",
-    //         "s16le",
-    //         "-ac",
-    //         "2",
-    //         "-ar",
-    //         "48000",
-    //         "-acodec",
-    //         "pcm_f32le",
-    //         "-",
-    //     ];
-
-    //     let ffmpeg = Command::new("ffmpeg")
-    //         .arg("-i")
-    //         .arg("-")
-    //         .args(ffmpeg_args)
-    //         .stdin(taken_stdout)
-    //         .stderr(Stdio::inherit())
-    //         .stdout(Stdio::piped())
-    //         .spawn()?;
-
-    //     Ok(songbird::input::Input::new(
-    //         true,
-    //         songbird::input::children_to_reader::<f32>(vec![input, ffmpeg]),
-    //         songbird::input::Codec::FloatPcm,
-    //         songbird::input::Container::Raw,
-    //         Default::default(),
-    //     ))
-    // }
-
-    pub async fn save_stdout(input: ChildStdout) -> anyhow::Result<()>{
-        let tee_args = [
-            "debug.txt",
-        ];
+            if let Some(position) = position {
+                guild_queue.items[position] = resolved;
+            }
+        });
+    }
 
-        let mut _tee = Command::new("tee")
-            .args(tee_args)
-            .stdin(input)
-            .stderr(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .spawn()?;
-        
-            // let _ = _tee.wait();
+    fn finish_prefetch (&self, guild_id: &Id<GuildMarker>) {
+        self.guild(guild_id).lock().unwrap().prefetching = false;
+    }
 
-        Ok(())
+    /// Token that is cancelled by the next `cancel` of the guild
+    pub fn cancel_token (&self, guild_id: &Id<GuildMarker>) -> CancelToken {
+        self.read(guild_id, |guild_queue| guild_queue.canceller.token())
     }
 
-    fn check_file (path: &Path) -> bool {
-        match fs::metadata(path) {
-            Ok(attributes) => {
-                !attributes.is_dir()
-            },
-            Err(_) => {
-                false
-            }
+    /// Kill the yt-dlp processes working for the guild, used when a skip or leave makes them useless
+    pub fn cancel (&self, guild_id: &Id<GuildMarker>) {
+        self.read(guild_id, |guild_queue| guild_queue.canceller.cancel());
+    }
+
+    /// Kill the yt-dlp processes of every guild before shutting down
+    pub fn cancel_all (&self) {
+        let guilds: Vec<Arc<Mutex<GuildQueue>>> = self.guilds.read().unwrap().values().cloned().collect();
+
+        for guild_queue in guilds {
+            guild_queue.lock().unwrap().canceller.cancel();
         }
     }
+
+    /// Media of the item from the backend that resolved it, searches are resolved first
+    pub async fn get_media (&self, guild_id: &Id<GuildMarker>, item: &PlaylistItem) -> anyhow::Result<Media> {
+        let mut cancel = self.cancel_token(guild_id);
+        let item = &self.resolve(item, &mut cancel).await?;
+
+        self.backends.for_item(item).fetch(item, &mut cancel).await
+    }
+
 }
 
 impl Default for SystemPlaylist {
@@ -523,7 +1090,7 @@ impl Default for SystemPlaylist {
 }
 
     
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct PlaylistItem {
     pub id: String,
@@ -536,5 +1103,177 @@ pub struct PlaylistItem {
     pub webpage_url: Option<String>,
     pub is_live: Option<bool>,
     pub was_live: Option<bool>,
-    pub backend: Option<YOUTUBE_DL_BACKEND>
-}
\ No newline at end of file
+    /// Song on air when the item is a radio
+    #[serde(default)]
+    pub stream_title: Option<String>,
+    /// Seconds where playback starts, from the timestamp of the url
+    #[serde(default)]
+    pub start_time: Option<f32>,
+    /// Name of the backend that resolved the item
+    #[serde(default, deserialize_with = "backend::deserialize_name")]
+    pub backend: Option<String>,
+    /// User that added the item to the queue
+    #[serde(default)]
+    pub requester: Option<Requester>,
+    /// Set when the item needs to be matched with a youtube video before playing it
+    #[serde(default)]
+    pub unresolved: Option<Unresolved>
+}
+
+/// How to find a playable url for an item
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Unresolved {
+    /// Spotify track, searched on youtube as `artists - title`
+    Spotify { query: String },
+    /// Text search, the first youtube result replaces the whole item
+    Search { query: String }
+}
+
+impl PlaylistItem {
+    /// Item with only what's needed to play it
+    pub fn new (id: &str, title: &str, original_url: &str, extractor: &str, backend: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            title: title.to_owned(),
+            original_url: original_url.to_owned(),
+            extractor: extractor.to_owned(),
+            thumbnail: None,
+            duration: None,
+            playlist_id: None,
+            webpage_url: None,
+            is_live: None,
+            was_live: None,
+            stream_title: None,
+            start_time: None,
+            backend: Some(backend.to_owned()),
+            requester: None,
+            unresolved: None
+        }
+    }
+
+    pub fn is_requested_by (&self, user_id: Id<UserMarker>) -> bool {
+        self.requester.as_ref().map(|requester| requester.id == user_id).unwrap_or(false)
+    }
+
+    /// Both items are the same unresolved item, used to find it again after resolving it
+    fn is_same_unresolved (&self, other: &PlaylistItem) -> bool {
+        self.unresolved.is_some() && self.unresolved == other.unresolved && self.id == other.id
+    }
+}
+
+/// Who added a song to the queue and when
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Requester {
+    pub id: Id<UserMarker>,
+    pub name: String,
+    pub avatar_url: String,
+    /// Unix timestamp in seconds
+    pub requested_at: u64
+}
+
+impl Requester {
+    pub fn new (id: Id<UserMarker>, name: String, avatar_url: String) -> Self {
+        let requested_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+
+        Self { id, name, avatar_url, requested_at }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::Arc;
+
+    use twilight_model::id::Id;
+
+    use super::{youtube_start_time, youtube_url_extractor, QueueWriter, YoutubeUrlType};
+    use crate::test_utils::temp_dir;
+
+    fn video(id: &str) -> YoutubeUrlType { YoutubeUrlType::Video(id.to_owned()) }
+    fn short(id: &str) -> YoutubeUrlType { YoutubeUrlType::Short(id.to_owned()) }
+    fn playlist(id: &str) -> YoutubeUrlType { YoutubeUrlType::Playlist(id.to_owned()) }
+
+    #[test]
+    fn youtube_urls() {
+        let cases = [
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", video("dQw4w9WgXcQ")),
+            ("https://youtube.com/watch?v=dQw4w9WgXcQ&t=42", video("dQw4w9WgXcQ")),
+            ("https://m.youtube.com/watch?v=dQw4w9WgXcQ", video("dQw4w9WgXcQ")),
+            ("https://music.youtube.com/watch?v=dQw4w9WgXcQ&feature=share", video("dQw4w9WgXcQ")),
+            ("https://youtu.be/dQw4w9WgXcQ", video("dQw4w9WgXcQ")),
+            ("https://youtu.be/dQw4w9WgXcQ?t=1m30s", video("dQw4w9WgXcQ")),
+            ("https://www.youtube.com/live/dQw4w9WgXcQ?feature=share", video("dQw4w9WgXcQ")),
+            ("https://www.youtube.com/embed/dQw4w9WgXcQ?start=30", video("dQw4w9WgXcQ")),
+            ("https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ", video("dQw4w9WgXcQ")),
+            ("https://www.youtube.com/v/dQw4w9WgXcQ", video("dQw4w9WgXcQ")),
+            ("https://www.youtube.com/shorts/abc_DEF-123", short("abc_DEF-123")),
+            ("https://youtube.com/shorts/abc_DEF-123?feature=share", short("abc_DEF-123")),
+            ("https://www.youtube.com/playlist?list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG", playlist("PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG")),
+            ("https://music.youtube.com/playlist?list=OLAK5uy_abc", playlist("OLAK5uy_abc")),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PLabc", playlist("PLabc")),
+            ("https://youtu.be/dQw4w9WgXcQ?list=PLabc", playlist("PLabc")),
+            ("https://www.youtube.com/embed/videoseries?list=PLabc", playlist("PLabc")),
+            ("https://www.youtube.com/", YoutubeUrlType::None),
+            ("https://youtube.com", YoutubeUrlType::None),
+            ("https://youtu.be/", YoutubeUrlType::None),
+            ("https://www.youtube.com/shorts/", YoutubeUrlType::None),
+            ("https://www.youtube.com/live", YoutubeUrlType::None),
+            ("https://www.youtube.com/watch", YoutubeUrlType::None),
+            ("https://www.youtube.com/watch?v=", YoutubeUrlType::None),
+            ("https://www.youtube.com/watch?v=<script>", YoutubeUrlType::None),
+            ("https://www.youtube.com/channel/UCuAXFkgsw1L7xaCfnd5JJOw", YoutubeUrlType::None),
+            ("https://notyoutube.com/watch?v=dQw4w9WgXcQ", YoutubeUrlType::None),
+            ("https://example.com/watch?v=dQw4w9WgXcQ", YoutubeUrlType::None),
+        ];
+
+        for (url, expected) in cases {
+            let parsed = url::Url::parse(url).unwrap();
+            assert_eq!(youtube_url_extractor(&parsed), expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn start_times() {
+        let cases = [
+            ("https://youtu.be/dQw4w9WgXcQ?t=90", Some(90.0)),
+            ("https://youtu.be/dQw4w9WgXcQ?t=90s", Some(90.0)),
+            ("https://youtu.be/dQw4w9WgXcQ?t=1m30s", Some(90.0)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=1h2m3s", Some(3723.0)),
+            ("https://www.youtube.com/embed/dQw4w9WgXcQ?start=45", Some(45.0)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ#t=30", Some(30.0)),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", None),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=0", None),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=abc", None),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=1m30", None),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=", None),
+        ];
+
+        for (url, expected) in cases {
+            let parsed = url::Url::parse(url).unwrap();
+            assert_eq!(youtube_start_time(&parsed), expected, "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_writes_are_batched() {
+        let dir = temp_dir("queues");
+        let writer = Arc::new(QueueWriter::new(&dir));
+
+        writer.schedule(Id::new(1), "first".to_owned());
+        writer.schedule(Id::new(1), "second".to_owned());
+        assert!(!dir.join("1.json").exists());
+
+        writer.flush();
+        assert_eq!(fs::read_to_string(dir.join("1.json")).unwrap(), "second");
+        assert!(!dir.join("1.json.tmp").exists());
+    }
+
+    #[test]
+    fn queue_written_right_away_without_runtime() {
+        let dir = temp_dir("queues-sync");
+        let writer = Arc::new(QueueWriter::new(&dir));
+
+        writer.schedule(Id::new(2), "queue".to_owned());
+        assert_eq!(fs::read_to_string(dir.join("2.json")).unwrap(), "queue");
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use os_pipe::PipeReader;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::ytdlp::{self, CancelToken};
+
+/// How long the server has to answer the probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A probe is reused for this long, routing an url and resolving it right after only asks the server once
+const PROBE_REUSE: Duration = Duration::from_secs(30);
+
+/// Recent probes by url, radios often limit how many listeners can connect
+static PROBES: Lazy<Mutex<HashMap<String, (Instant, HttpProbe)>>> = Lazy::new(Default::default);
+
+/// Content types of playlist files, they point to the stream but are not audio
+const PLAYLIST_TYPES: [&str; 4] = ["audio/x-mpegurl", "audio/mpegurl", "audio/x-scpls", "application/pls+xml"];
+
+/// What the server says about an url before downloading it
+#[derive(Debug, Clone)]
+pub struct HttpProbe {
+    pub content_type: Option<String>,
+    /// Station name of Icecast and Shoutcast streams
+    pub name: Option<String>,
+    /// Icecast and Shoutcast streams never end, files served without a length are not radios
+    pub live: bool
+}
+
+impl HttpProbe {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(|value| value.trim().to_owned());
+
+        let content_type = header(CONTENT_TYPE.as_str())
+            .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_lowercase());
+        let icy = headers.keys().any(|name| name.as_str().starts_with("icy-"));
+
+        Self {
+            name: header("icy-name").filter(|name| !name.is_empty()),
+            live: icy,
+            content_type
+        }
+    }
+
+    /// Audio that symphonia can play directly
+    pub fn is_audio(&self) -> bool {
+        match self.content_type.as_deref() {
+            Some(content_type) => {
+                (content_type.starts_with("audio/") || content_type == "application/ogg") && !PLAYLIST_TYPES.contains(&content_type)
+            },
+            None => false,
+        }
+    }
+}
+
+/// Ask the server what the url is, only the headers are read. The answer is reused for `PROBE_REUSE`
+pub async fn probe(url: &str) -> anyhow::Result<HttpProbe> {
+    if let Some((probed_at, probe)) = PROBES.lock().unwrap().get(url) {
+        if probed_at.elapsed() < PROBE_REUSE {
+            return Ok(probe.clone())
+        }
+    }
+
+    let response = reqwest::Client::new()
+        .get(url)
+        .header("Icy-MetaData", "1")
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let probe = HttpProbe::from_headers(response.headers());
+
+    let mut probes = PROBES.lock().unwrap();
+    probes.retain(|_, (probed_at, _)| probed_at.elapsed() < PROBE_REUSE);
+    probes.insert(url.to_owned(), (Instant::now(), probe.clone()));
+
+    Ok(probe)
+}
+
+/// Play a radio stream, the audio goes to the pipe without the ICY metadata
+/// and the titles of the songs are sent when they change
+pub async fn open(url: &str, mut cancel: CancelToken) -> anyhow::Result<(PipeReader, Option<String>, UnboundedReceiver<String>)> {
+    let mut response = reqwest::Client::new()
+        .get(url)
+        .header("Icy-MetaData", "1")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let metaint = response.headers()
+        .get("icy-metaint")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|metaint| *metaint > 0);
+    let content_type = HttpProbe::from_headers(response.headers()).content_type;
+
+    let (reader, mut writer) = os_pipe::pipe()?;
+    let (titles_sender, titles) = mpsc::unbounded_channel();
+    let mut demuxer = IcyDemuxer::new(metaint);
+
+    // Writing to the pipe blocks while the track is paused so the copy gets its own thread
+    let runtime = tokio::runtime::Handle::current();
+    std::thread::spawn(move || runtime.block_on(async move {
+        loop {
+            let chunk = tokio::select! {
+                chunk = tokio::time::timeout(ytdlp::STALL_TIMEOUT, response.chunk()) => chunk,
+                _ = cancel.cancelled() => break,
+            };
+
+            let chunk = match chunk {
+                Ok(Ok(Some(chunk))) => chunk,
+                // The stream ended, failed or stalled
+                _ => break,
+            };
+
+            let (audio, title) = demuxer.push(&chunk);
+
+            if let Some(title) = title {
+                let _ = titles_sender.send(title);
+            }
+            // Playback stopped
+            if writer.write_all(&audio).is_err() {
+                break
+            }
+        }
+    }));
+
+    Ok((reader, content_type, titles))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IcyState {
+    /// Bytes of audio left before the next metadata block
+    Audio(usize),
+    /// The next byte is the metadata length
+    Length,
+    /// Bytes of metadata left
+    Metadata(usize)
+}
+
+/// Splits an ICY stream into audio and metadata, every `metaint` bytes of audio there is
+/// a length byte and `length * 16` bytes of metadata like `StreamTitle='Artist - Song';`
+pub struct IcyDemuxer {
+    metaint: Option<usize>,
+    state: IcyState,
+    metadata: Vec<u8>,
+    title: Option<String>
+}
+
+impl IcyDemuxer {
+    /// Without `metaint` the stream has no metadata and everything is audio
+    pub fn new(metaint: Option<usize>) -> Self {
+        Self {
+            metaint,
+            state: IcyState::Audio(metaint.unwrap_or(0)),
+            metadata: Vec::new(),
+            title: None
+        }
+    }
+
+    /// Returns the audio in the chunk and the new title if it changed
+    pub fn push(&mut self, mut chunk: &[u8]) -> (Vec<u8>, Option<String>) {
+        let metaint = match self.metaint {
+            Some(metaint) => metaint,
+            None => return (chunk.to_vec(), None),
+        };
+
+        let mut audio = Vec::with_capacity(chunk.len());
+        let mut new_title = None;
+
+        while !chunk.is_empty() {
+            match self.state {
+                IcyState::Audio(left) => {
+                    let take = left.min(chunk.len());
+                    audio.extend_from_slice(&chunk[..take]);
+                    chunk = &chunk[take..];
+                    self.state = if take == left { IcyState::Length } else { IcyState::Audio(left - take) };
+                },
+                IcyState::Length => {
+                    let length = chunk[0] as usize * 16;
+                    chunk = &chunk[1..];
+                    self.metadata.clear();
+                    self.state = if length == 0 { IcyState::Audio(metaint) } else { IcyState::Metadata(length) };
+                },
+                IcyState::Metadata(left) => {
+                    let take = left.min(chunk.len());
+                    self.metadata.extend_from_slice(&chunk[..take]);
+                    chunk = &chunk[take..];
+
+                    if take < left {
+                        self.state = IcyState::Metadata(left - take);
+                        continue
+                    }
+
+                    self.state = IcyState::Audio(metaint);
+
+                    if let Some(title) = stream_title(&String::from_utf8_lossy(&self.metadata)) {
+                        if self.title.as_ref() != Some(&title) {
+                            self.title = Some(title.to_owned());
+                            new_title = Some(title);
+                        }
+                    }
+                },
+            }
+        }
+
+        (audio, new_title)
+    }
+}
+
+/// The `StreamTitle` of an ICY metadata block, None when it's missing or empty
+fn stream_title(metadata: &str) -> Option<String> {
+    let start = metadata.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &metadata[start..];
+    let end = rest.find("';").unwrap_or_else(|| rest.trim_end_matches('\0').trim_end_matches('\'').len());
+
+    let title = rest[..end].trim();
+    if title.is_empty() { None } else { Some(title.to_owned()) }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, TRANSFER_ENCODING};
+
+    use super::{probe, stream_title, HttpProbe, IcyDemuxer};
+    use crate::test_utils::{MockServer, MockRoute};
+
+    /// Metadata block with its length byte, padded to a multiple of 16
+    fn metadata_block(text: &str) -> Vec<u8> {
+        let mut block = text.as_bytes().to_vec();
+        block.resize((block.len() + 15) / 16 * 16, 0);
+
+        let mut bytes = vec![(block.len() / 16) as u8];
+        bytes.extend(block);
+        bytes
+    }
+
+    #[test]
+    fn titles() {
+        assert_eq!(stream_title("StreamTitle='Artist - Song';StreamUrl='';").as_deref(), Some("Artist - Song"));
+        assert_eq!(stream_title("StreamTitle='Song';\0\0\0").as_deref(), Some("Song"));
+        assert_eq!(stream_title("StreamTitle='';"), None);
+        assert_eq!(stream_title("StreamUrl='http://example.com';"), None);
+    }
+
+    #[test]
+    fn demuxer_strips_metadata() {
+        let mut stream = b"abcd".to_vec();
+        stream.extend(metadata_block("StreamTitle='First';"));
+        stream.extend(b"efgh");
+        stream.push(0);
+        stream.extend(b"ijkl");
+        stream.extend(metadata_block("StreamTitle='First';"));
+        stream.extend(b"mn");
+
+        let mut demuxer = IcyDemuxer::new(Some(4));
+        let mut audio = Vec::new();
+        let mut titles = Vec::new();
+
+        // Split in small chunks so the blocks are cut in the middle
+        for chunk in stream.chunks(3) {
+            let (chunk_audio, title) = demuxer.push(chunk);
+            audio.extend(chunk_audio);
+            titles.extend(title);
+        }
+
+        assert_eq!(audio, b"abcdefghijklmn");
+        assert_eq!(titles, vec!["First"]);
+    }
+
+    #[test]
+    fn radio_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("audio/mpeg"));
+        headers.insert("icy-name", HeaderValue::from_static("Radio"));
+
+        let probe = HttpProbe::from_headers(&headers);
+
+        assert!(probe.live);
+        assert!(probe.is_audio());
+        assert_eq!(probe.name.as_deref(), Some("Radio"));
+    }
+
+    #[test]
+    fn chunked_file_is_not_live() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("audio/ogg; codecs=vorbis"));
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+
+        let probe = HttpProbe::from_headers(&headers);
+
+        assert!(!probe.live);
+        assert!(probe.is_audio());
+        assert_eq!(probe.content_type.as_deref(), Some("audio/ogg"));
+    }
+
+    #[tokio::test]
+    async fn probe_is_reused() {
+        let server = MockServer::start(vec![
+            MockRoute::new("GET", "/stream", 200, ""),
+        ]);
+        let url = format!("{}/stream", server.url);
+
+        probe(&url).await.unwrap();
+        probe(&url).await.unwrap();
+
+        assert_eq!(server.requests(), vec!["GET /stream"]);
+    }
+
+    #[test]
+    fn demuxer_without_metadata() {
+        let mut demuxer = IcyDemuxer::new(None);
+
+        assert_eq!(demuxer.push(b"audio"), (b"audio".to_vec(), None));
+    }
+}
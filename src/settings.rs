@@ -0,0 +1,393 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use twilight_interactions::command::{CommandOption, CreateOption};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{GuildMarker, ChannelMarker, RoleMarker};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::helpers;
+
+/// Directory where the settings of every guild are saved as `<guild_id>.json`
+const SETTINGS_PATH: &str = "data/settings";
+
+/// Settings of a guild, `None` means the bot default is used
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+    /// Volume in percent used when joining a voice channel
+    pub default_volume: Option<u8>,
+    /// Members with this role skip songs without a vote
+    pub dj_role: Option<Id<RoleMarker>>,
+    pub max_queue_length: Option<usize>,
+    /// Songs a single user can have waiting in the queue
+    pub max_songs_per_user: Option<usize>,
+    /// Longest song that can be added in seconds
+    pub max_song_duration: Option<u64>,
+    /// Channel for the bot messages instead of the channel where the command was used
+    pub announce_channel: Option<Id<ChannelMarker>>,
+    /// Seconds paused or idle before leaving the voice channel
+    pub idle_timeout: Option<u64>,
+    /// Hosts that can be played from urls, every host is allowed when empty
+    pub allowed_hosts: Vec<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CommandOption, CreateOption)]
+pub enum SettingKey {
+    #[option(name = "Default volume", value = "default_volume")]
+    DefaultVolume,
+    #[option(name = "DJ role", value = "dj_role")]
+    DjRole,
+    #[option(name = "Max queue length", value = "max_queue_length")]
+    MaxQueueLength,
+    #[option(name = "Max songs per user", value = "max_songs_per_user")]
+    MaxSongsPerUser,
+    #[option(name = "Max song duration", value = "max_song_duration")]
+    MaxSongDuration,
+    #[option(name = "Announce channel", value = "announce_channel")]
+    AnnounceChannel,
+    #[option(name = "Idle leave timeout", value = "idle_timeout")]
+    IdleTimeout,
+    #[option(name = "Allowed source hosts", value = "allowed_hosts")]
+    AllowedHosts
+}
+
+impl SettingKey {
+    pub const ALL: [SettingKey; 8] = [
+        SettingKey::DefaultVolume,
+        SettingKey::DjRole,
+        SettingKey::MaxQueueLength,
+        SettingKey::MaxSongsPerUser,
+        SettingKey::MaxSongDuration,
+        SettingKey::AnnounceChannel,
+        SettingKey::IdleTimeout,
+        SettingKey::AllowedHosts,
+    ];
+
+    pub fn name (&self) -> &str {
+        match self {
+            SettingKey::DefaultVolume => "Default volume",
+            SettingKey::DjRole => "DJ role",
+            SettingKey::MaxQueueLength => "Max queue length",
+            SettingKey::MaxSongsPerUser => "Max songs per user",
+            SettingKey::MaxSongDuration => "Max song duration",
+            SettingKey::AnnounceChannel => "Announce channel",
+            SettingKey::IdleTimeout => "Idle leave timeout",
+            SettingKey::AllowedHosts => "Allowed source hosts",
+        }
+    }
+}
+
+impl GuildSettings {
+    /// Parse and store the value of a setting
+    pub fn set (&mut self, key: SettingKey, value: &str) -> anyhow::Result<()> {
+        let value = value.trim();
+
+        match key {
+            SettingKey::DefaultVolume => {
+                let volume = value.trim_end_matches('%').parse::<u8>().ok().filter(|volume| *volume <= 200);
+                self.default_volume = Some(volume.ok_or_else(|| anyhow!("The volume must be a number from 0 to 200"))?);
+            },
+            SettingKey::DjRole => {
+                let role_id = parse_mention(value, "<@&").ok_or_else(|| anyhow!("Use a role mention or a role id"))?;
+                self.dj_role = Some(role_id);
+            },
+            SettingKey::MaxQueueLength => {
+                let length = value.parse::<usize>().ok().filter(|length| *length > 0);
+                self.max_queue_length = Some(length.ok_or_else(|| anyhow!("The queue length must be a number greater than 0"))?);
+            },
+            SettingKey::MaxSongsPerUser => {
+                let songs = value.parse::<usize>().ok().filter(|songs| *songs > 0);
+                self.max_songs_per_user = Some(songs.ok_or_else(|| anyhow!("The songs per user must be a number greater than 0"))?);
+            },
+            SettingKey::MaxSongDuration => {
+                let duration = parse_seconds(value).filter(|duration| *duration > 0);
+                self.max_song_duration = Some(duration.ok_or_else(|| anyhow!("Use a duration like `10:00` or a number of seconds"))?);
+            },
+            SettingKey::AnnounceChannel => {
+                let channel_id = parse_mention(value, "<#").ok_or_else(|| anyhow!("Use a channel mention or a channel id"))?;
+                self.announce_channel = Some(channel_id);
+            },
+            SettingKey::IdleTimeout => {
+                let timeout = parse_seconds(value).filter(|timeout| *timeout > 0);
+                self.idle_timeout = Some(timeout.ok_or_else(|| anyhow!("Use a duration like `5:00` or a number of seconds"))?);
+            },
+            SettingKey::AllowedHosts => {
+                self.allowed_hosts = value
+                    .split(',')
+                    .map(|host| host.trim().trim_start_matches("www.").to_lowercase())
+                    .filter(|host| !host.is_empty())
+                    .collect();
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Go back to the default value of a setting
+    pub fn reset (&mut self, key: SettingKey) {
+        match key {
+            SettingKey::DefaultVolume => self.default_volume = None,
+            SettingKey::DjRole => self.dj_role = None,
+            SettingKey::MaxQueueLength => self.max_queue_length = None,
+            SettingKey::MaxSongsPerUser => self.max_songs_per_user = None,
+            SettingKey::MaxSongDuration => self.max_song_duration = None,
+            SettingKey::AnnounceChannel => self.announce_channel = None,
+            SettingKey::IdleTimeout => self.idle_timeout = None,
+            SettingKey::AllowedHosts => self.allowed_hosts.clear(),
+        }
+    }
+
+    /// Human readable value of a setting
+    pub fn display (&self, key: SettingKey) -> String {
+        let value = match key {
+            SettingKey::DefaultVolume => self.default_volume.map(|volume| format!("{}%", volume)),
+            SettingKey::DjRole => self.dj_role.map(|role_id| format!("<@&{}>", role_id)),
+            SettingKey::MaxQueueLength => self.max_queue_length.map(|length| format!("{} songs", length)),
+            SettingKey::MaxSongsPerUser => self.max_songs_per_user.map(|songs| format!("{} songs", songs)),
+            SettingKey::MaxSongDuration => self.max_song_duration.map(format_seconds),
+            SettingKey::AnnounceChannel => self.announce_channel.map(|channel_id| format!("<#{}>", channel_id)),
+            SettingKey::IdleTimeout => self.idle_timeout.map(format_seconds),
+            SettingKey::AllowedHosts => if self.allowed_hosts.is_empty() { None } else { Some(self.allowed_hosts.join(", ")) },
+        };
+
+        value.unwrap_or_else(|| "Default".into())
+    }
+
+    /// Checks a song against the max song duration, songs with an unknown duration are allowed
+    pub fn is_duration_allowed (&self, duration: Option<f32>) -> bool {
+        match (self.max_song_duration, duration) {
+            (Some(max_duration), Some(duration)) => duration as u64 <= max_duration,
+            _ => true,
+        }
+    }
+
+    /// Checks if a url host can be played, subdomains of an allowed host are allowed too
+    pub fn is_host_allowed (&self, host: &str) -> bool {
+        let host = host.trim_start_matches("www.").to_lowercase();
+
+        self.allowed_hosts.is_empty() || self.allowed_hosts.iter().any(|allowed| {
+            host == *allowed || host.ends_with(&format!(".{}", allowed))
+        })
+    }
+
+    /// Checks the host of the url a song plays from, local files have no host and are always allowed
+    pub fn is_url_allowed (&self, url: &str) -> bool {
+        match url::Url::parse(url).ok().as_ref().and_then(|url| url.host_str()) {
+            Some(host) => self.is_host_allowed(host),
+            None => true,
+        }
+    }
+}
+
+/// Parse a mention like `<#123>` or a plain id
+fn parse_mention<T> (value: &str, prefix: &str) -> Option<Id<T>> {
+    let id = value.strip_prefix(prefix).and_then(|value| value.strip_suffix('>')).unwrap_or(value);
+    id.parse::<u64>().ok().and_then(Id::new_checked)
+}
+
+/// Parse a number of seconds or a duration like `1:30`
+fn parse_seconds (value: &str) -> Option<u64> {
+    value
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()
+        .filter(|parts| !parts.is_empty() && parts.len() <= 3)
+        .map(|parts| parts.iter().fold(0, |total, part| total * 60 + part))
+}
+
+fn format_seconds (seconds: u64) -> String {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Settings of every guild, saved in `data/settings`
+#[derive(Debug)]
+pub struct SettingsStore {
+    dir: PathBuf,
+    guilds: HashMap<Id<GuildMarker>, GuildSettings>
+}
+
+impl SettingsStore {
+    pub fn load () -> Self {
+        Self::load_from(SETTINGS_PATH)
+    }
+
+    /// Load the settings saved as `<dir>/<guild_id>.json`, they are saved there too
+    fn load_from (dir: impl Into<PathBuf>) -> Self {
+        let mut store = Self {
+            dir: dir.into(),
+            guilds: HashMap::new()
+        };
+
+        let entries = match fs::read_dir(&store.dir) {
+            Ok(entries) => entries,
+            Err(_) => return store,
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            let guild_id = match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()).and_then(Id::new_checked) {
+                Some(guild_id) => guild_id,
+                None => continue,
+            };
+
+            let settings = fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| serde_json::from_str::<GuildSettings>(&json).map_err(anyhow::Error::from));
+
+            match settings {
+                Ok(settings) => { store.guilds.insert(guild_id, settings); },
+                Err(err) => tracing::warn!("cannot load settings {}: {:?}", path.display(), err),
+            }
+        }
+
+        store
+    }
+
+    /// Returns the settings of the guild, the defaults if it never changed them
+    pub fn get (&self, guild_id: &Id<GuildMarker>) -> GuildSettings {
+        self.guilds.get(guild_id).cloned().unwrap_or_default()
+    }
+
+    pub fn set (&mut self, guild_id: &Id<GuildMarker>, key: SettingKey, value: &str) -> anyhow::Result<()> {
+        self.guilds.entry(*guild_id).or_default().set(key, value)?;
+        self.persist(guild_id);
+        Ok(())
+    }
+
+    /// Reset a setting, or all of them when `key` is None
+    pub fn reset (&mut self, guild_id: &Id<GuildMarker>, key: Option<SettingKey>) {
+        match key {
+            Some(key) => self.guilds.entry(*guild_id).or_default().reset(key),
+            None => { self.guilds.insert(*guild_id, GuildSettings::default()); },
+        }
+
+        self.persist(guild_id);
+    }
+
+    fn persist (&self, guild_id: &Id<GuildMarker>) {
+        let fpath = self.dir.join(format!("{}.json", guild_id)).to_string_lossy().into_owned();
+
+        let result = match self.guilds.get(guild_id) {
+            Some(settings) => serde_json::to_string(settings)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| helpers::write_json(&fpath, json).map_err(anyhow::Error::from)),
+            None => Ok(()),
+        };
+
+        if let Err(err) = result {
+            tracing::warn!("cannot persist settings {}: {:?}", fpath, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use twilight_model::id::Id;
+    use twilight_model::id::marker::{ChannelMarker, RoleMarker};
+
+    use super::{parse_mention, parse_seconds, GuildSettings, SettingKey, SettingsStore};
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn seconds() {
+        assert_eq!(parse_seconds("90"), Some(90));
+        assert_eq!(parse_seconds("1:30"), Some(90));
+        assert_eq!(parse_seconds("1:00:05"), Some(3605));
+        assert_eq!(parse_seconds("1:2:3:4"), None);
+        assert_eq!(parse_seconds("ten"), None);
+        assert_eq!(parse_seconds(""), None);
+    }
+
+    #[test]
+    fn mentions() {
+        assert_eq!(parse_mention::<ChannelMarker>("<#123>", "<#"), Some(Id::new(123)));
+        assert_eq!(parse_mention::<RoleMarker>("<@&456>", "<@&"), Some(Id::new(456)));
+        assert_eq!(parse_mention::<ChannelMarker>("789", "<#"), Some(Id::new(789)));
+        assert_eq!(parse_mention::<ChannelMarker>("<#0>", "<#"), None);
+        assert_eq!(parse_mention::<ChannelMarker>("<@&456>", "<#"), None);
+    }
+
+    #[test]
+    fn set_and_reset() {
+        let mut settings = GuildSettings::default();
+
+        settings.set(SettingKey::DefaultVolume, "150%").unwrap();
+        settings.set(SettingKey::MaxSongDuration, "10:00").unwrap();
+        settings.set(SettingKey::DjRole, "<@&42>").unwrap();
+        settings.set(SettingKey::AllowedHosts, "www.YouTube.com, soundcloud.com,").unwrap();
+
+        assert_eq!(settings.default_volume, Some(150));
+        assert_eq!(settings.max_song_duration, Some(600));
+        assert_eq!(settings.dj_role, Some(Id::new(42)));
+        assert_eq!(settings.allowed_hosts, vec!["youtube.com", "soundcloud.com"]);
+        assert_eq!(settings.display(SettingKey::MaxSongDuration), "10:00");
+
+        assert!(settings.set(SettingKey::DefaultVolume, "300").is_err());
+        assert!(settings.set(SettingKey::MaxQueueLength, "0").is_err());
+        // A wrong value keeps the previous one
+        assert_eq!(settings.default_volume, Some(150));
+
+        settings.reset(SettingKey::DefaultVolume);
+        settings.reset(SettingKey::AllowedHosts);
+        assert_eq!(settings.default_volume, None);
+        assert!(settings.allowed_hosts.is_empty());
+        assert_eq!(settings.display(SettingKey::DefaultVolume), "Default");
+    }
+
+    #[test]
+    fn allowed_hosts() {
+        let mut settings = GuildSettings::default();
+        assert!(settings.is_host_allowed("example.com"));
+
+        settings.set(SettingKey::AllowedHosts, "youtube.com").unwrap();
+        assert!(settings.is_host_allowed("youtube.com"));
+        assert!(settings.is_host_allowed("www.youtube.com"));
+        assert!(settings.is_host_allowed("music.YouTube.com"));
+        assert!(!settings.is_host_allowed("notyoutube.com"));
+        assert!(!settings.is_host_allowed("youtube.com.example.org"));
+
+        assert!(settings.is_url_allowed("https://m.youtube.com/watch?v=abc"));
+        assert!(!settings.is_url_allowed("https://soundcloud.com/artist/song"));
+        // Local files have no host
+        assert!(settings.is_url_allowed("file:///media/song.mp3"));
+    }
+
+    #[test]
+    fn durations() {
+        let mut settings = GuildSettings::default();
+        assert!(settings.is_duration_allowed(Some(36000.0)));
+
+        settings.set(SettingKey::MaxSongDuration, "60").unwrap();
+        assert!(settings.is_duration_allowed(Some(60.0)));
+        assert!(!settings.is_duration_allowed(Some(61.0)));
+        assert!(settings.is_duration_allowed(None));
+    }
+
+    #[test]
+    fn store_round_trip() {
+        let dir = temp_dir("settings");
+        let guild_id = Id::new(7);
+
+        let mut store = SettingsStore::load_from(&dir);
+        store.set(&guild_id, SettingKey::MaxQueueLength, "20").unwrap();
+        store.set(&guild_id, SettingKey::IdleTimeout, "5:00").unwrap();
+        assert!(store.set(&guild_id, SettingKey::IdleTimeout, "soon").is_err());
+
+        // Files that are not settings are skipped
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        fs::write(dir.join("8.json"), "not json").unwrap();
+
+        let loaded = SettingsStore::load_from(&dir);
+        let settings = loaded.get(&guild_id);
+        assert_eq!(settings.max_queue_length, Some(20));
+        assert_eq!(settings.idle_timeout, Some(300));
+        assert_eq!(loaded.get(&Id::new(8)).max_queue_length, None);
+
+        store.reset(&guild_id, None);
+        assert_eq!(SettingsStore::load_from(&dir).get(&guild_id).max_queue_length, None);
+    }
+}
@@ -0,0 +1,397 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+const API_BASE: &str = "https://api.spotify.com/v1";
+const ACCOUNTS_BASE: &str = "https://accounts.spotify.com";
+
+/// Spotify Web API client using the client credentials flow, only public metadata is available
+pub struct SpotifyAPI {
+    client_id: String,
+    client_secret: String,
+    api_base: String,
+    accounts_base: String,
+    /// Access token and when it expires
+    token: Mutex<Option<(String, Instant)>>
+}
+
+// The credentials and the token are left out of the logs
+impl fmt::Debug for SpotifyAPI {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpotifyAPI")
+            .field("api_base", &self.api_base)
+            .field("accounts_base", &self.accounts_base)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SpotifyAPI {
+    pub fn new (client_id: &str, client_secret: &str) -> Self {
+        Self::with_base_urls(client_id, client_secret, API_BASE, ACCOUNTS_BASE)
+    }
+
+    /// Client that talks to other servers instead of the Spotify ones
+    pub fn with_base_urls (client_id: &str, client_secret: &str, api_base: &str, accounts_base: &str) -> Self {
+        Self {
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_owned(),
+            api_base: api_base.trim_end_matches('/').to_owned(),
+            accounts_base: accounts_base.trim_end_matches('/').to_owned(),
+            token: Mutex::new(None)
+        }
+    }
+
+    /// Client with the `SPOTIFY_CLIENT_ID` and `SPOTIFY_CLIENT_SECRET` credentials, None if they are missing
+    pub fn from_env () -> Option<Self> {
+        let client_id = std::env::var("SPOTIFY_CLIENT_ID").ok().filter(|value| !value.is_empty())?;
+        let client_secret = std::env::var("SPOTIFY_CLIENT_SECRET").ok().filter(|value| !value.is_empty())?;
+
+        Some(Self::new(&client_id, &client_secret))
+    }
+
+    pub async fn track (&self, id: &str) -> anyhow::Result<SpotifyTrack> {
+        self.get::<SpotifyTrack>(&format!("{}/tracks/{}", &self.api_base, id)).await
+    }
+
+    /// Tracks of an album up to `max_items`, the album cover is copied to the tracks
+    pub async fn album (&self, id: &str, max_items: usize) -> anyhow::Result<SpotifyTracks> {
+        let album = self.get::<SpotifyAlbum>(&format!("{}/albums/{}", &self.api_base, id)).await?;
+
+        let mut tracks = album.tracks.items;
+        let mut next = album.tracks.next;
+
+        while let Some(next_url) = next.as_ref().filter(|_| tracks.len() < max_items) {
+            let page = self.get::<SpotifyPage<SpotifyTrack>>(next_url).await?;
+            tracks.extend(page.items);
+            next = page.next;
+        }
+
+        let album_info = SpotifyTrackAlbum {
+            name: album.name,
+            images: album.images
+        };
+
+        for track in tracks.iter_mut() {
+            track.album = Some(album_info.clone());
+        }
+
+        Ok(SpotifyTracks::capped(tracks, next.is_some(), max_items, "album"))
+    }
+
+    /// Tracks of a playlist up to `max_items`, local files and removed tracks are skipped
+    pub async fn playlist (&self, id: &str, max_items: usize) -> anyhow::Result<SpotifyTracks> {
+        let mut tracks = Vec::new();
+        let mut next = Some(format!("{}/playlists/{}/tracks?limit=100", &self.api_base, id));
+
+        while let Some(next_url) = next.as_ref().filter(|_| tracks.len() < max_items) {
+            let page = self.get::<SpotifyPage<SpotifyPlaylistItem>>(next_url).await?;
+            tracks.extend(page.items.into_iter().filter_map(|item| item.track).filter(|track| track.id.is_some()));
+            next = page.next;
+        }
+
+        Ok(SpotifyTracks::capped(tracks, next.is_some(), max_items, "playlist"))
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>> (&self, url: &str) -> anyhow::Result<T> {
+        let token = self.access_token().await?;
+
+        let response = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+
+        if !status.is_success() {
+            return Err(match serde_json::from_str::<SpotifyErrorResponse>(&text) {
+                Ok(error) => anyhow!("Spotify error {}: {}", error.error.status, error.error.message),
+                Err(_) => anyhow!("Spotify error {}", status),
+            })
+        }
+
+        Ok(serde_json::from_str::<T>(&text)?)
+    }
+
+    /// Returns the cached access token or requests a new one when it expired
+    async fn access_token (&self) -> anyhow::Result<String> {
+        let mut token = self.token.lock().await;
+
+        if let Some((access_token, expires_at)) = token.as_ref() {
+            if Instant::now() < *expires_at {
+                return Ok(access_token.to_owned())
+            }
+        }
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/api/token", &self.accounts_base))
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("grant_type", "client_credentials")])
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+
+        if !status.is_success() {
+            return Err(anyhow!("Spotify authentication failed {}: {}", status, text))
+        }
+
+        let new_token = serde_json::from_str::<SpotifyToken>(&text)?;
+        // Renew a bit earlier so the token doesn't expire in the middle of a request
+        let expires_at = Instant::now() + Duration::from_secs(new_token.expires_in.saturating_sub(60));
+
+        *token = Some((new_token.access_token.to_owned(), expires_at));
+
+        Ok(new_token.access_token)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotifyToken {
+    access_token: String,
+    expires_in: u64
+}
+
+// Error
+
+#[derive(Deserialize, Debug)]
+struct SpotifyErrorResponse {
+    error: SpotifyError
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotifyError {
+    status: u16,
+    message: String
+}
+
+// Tracks
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpotifyTrack {
+    /// None for local files
+    pub id: Option<String>,
+    pub name: String,
+    pub duration_ms: u64,
+    pub artists: Vec<SpotifyArtist>,
+    /// Missing in album tracks, filled with the album data
+    pub album: Option<SpotifyTrackAlbum>,
+    pub external_urls: SpotifyExternalUrls
+}
+
+impl SpotifyTrack {
+    /// `Artist, Artist - Title`, used to find the song on youtube
+    pub fn search_query (&self) -> String {
+        let artists: Vec<&str> = self.artists.iter().map(|artist| artist.name.as_str()).collect();
+
+        if artists.is_empty() {
+            self.name.to_owned()
+        } else {
+            format!("{} - {}", artists.join(", "), &self.name)
+        }
+    }
+
+    pub fn thumbnail (&self) -> Option<String> {
+        self.album.as_ref().and_then(|album| album.images.first()).map(|image| image.url.to_owned())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpotifyArtist {
+    pub name: String
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpotifyTrackAlbum {
+    pub name: String,
+    pub images: Vec<SpotifyImage>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpotifyImage {
+    pub url: String
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SpotifyExternalUrls {
+    pub spotify: Option<String>
+}
+
+// Albums and playlists
+
+/// Tracks of an album or a playlist, with a warning when they were cut at the item cap
+#[derive(Debug)]
+pub struct SpotifyTracks {
+    pub tracks: Vec<SpotifyTrack>,
+    pub warning: Option<String>
+}
+
+impl SpotifyTracks {
+    fn capped (mut tracks: Vec<SpotifyTrack>, has_next: bool, max_items: usize, kind: &str) -> Self {
+        let warning = (has_next || tracks.len() > max_items)
+            .then(|| format!("The {} has more than {} songs, only the first {} were loaded", kind, max_items, max_items.min(tracks.len())));
+        tracks.truncate(max_items);
+
+        Self { tracks, warning }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotifyAlbum {
+    name: String,
+    images: Vec<SpotifyImage>,
+    tracks: SpotifyPage<SpotifyTrack>
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotifyPage<T> {
+    items: Vec<T>,
+    next: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotifyPlaylistItem {
+    track: Option<SpotifyTrack>
+}
+
+#[cfg(test)]
+mod test {
+    use super::SpotifyAPI;
+    use crate::test_utils::{MockServer, MockRoute};
+
+    const TOKEN: &str = r#"{"access_token":"token","token_type":"Bearer","expires_in":3600}"#;
+
+    fn track_json(id: &str, name: &str) -> String {
+        format!(
+            r#"{{"id":"{id}","name":"{name}","duration_ms":200000,"artists":[{{"name":"Artist"}},{{"name":"Other"}}],"external_urls":{{"spotify":"https://open.spotify.com/track/{id}"}}}}"#
+        )
+    }
+
+    fn api(server: &MockServer) -> SpotifyAPI {
+        SpotifyAPI::with_base_urls("id", "secret", &format!("{}/v1", server.url), &server.url)
+    }
+
+    #[tokio::test]
+    async fn track() {
+        let server = MockServer::start(vec![
+            MockRoute::new("POST", "/api/token", 200, TOKEN),
+            MockRoute::new("GET", "/v1/tracks/abc", 200, &track_json("abc", "Song")),
+        ]);
+
+        let track = api(&server).track("abc").await.unwrap();
+
+        assert_eq!(track.id.as_deref(), Some("abc"));
+        assert_eq!(track.duration_ms, 200000);
+        assert_eq!(track.search_query(), "Artist, Other - Song");
+        assert_eq!(server.requests(), vec!["POST /api/token", "GET /v1/tracks/abc"]);
+    }
+
+    #[tokio::test]
+    async fn token_is_reused() {
+        let server = MockServer::start(vec![
+            MockRoute::new("POST", "/api/token", 200, TOKEN),
+            MockRoute::new("GET", "/v1/tracks/abc", 200, &track_json("abc", "Song")),
+        ]);
+
+        let api = api(&server);
+        api.track("abc").await.unwrap();
+        api.track("abc").await.unwrap();
+
+        assert_eq!(server.requests().iter().filter(|request| request.starts_with("POST")).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn album_pages() {
+        let album = format!(
+            r#"{{"name":"Album","images":[{{"url":"https://i.scdn.co/cover"}}],"tracks":{{"items":[{}],"next":"{{base}}/v1/albums/xyz/tracks?offset=1"}}}}"#,
+            track_json("one", "First")
+        );
+        let page = format!(r#"{{"items":[{}],"next":null}}"#, track_json("two", "Second"));
+
+        let server = MockServer::start(vec![
+            MockRoute::new("POST", "/api/token", 200, TOKEN),
+            MockRoute::new("GET", "/v1/albums/xyz/tracks?offset=1", 200, &page),
+            MockRoute::new("GET", "/v1/albums/xyz", 200, &album),
+        ]);
+
+        let album = api(&server).album("xyz", 10).await.unwrap();
+        let tracks = album.tracks;
+
+        assert!(album.warning.is_none());
+        let names: Vec<&str> = tracks.iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, vec!["First", "Second"]);
+        assert_eq!(tracks[1].thumbnail().as_deref(), Some("https://i.scdn.co/cover"));
+    }
+
+    #[tokio::test]
+    async fn playlist_skips_missing_tracks() {
+        let first = format!(
+            r#"{{"items":[{{"track":{}}},{{"track":null}}],"next":"{{base}}/v1/playlists/list/tracks?offset=100&limit=100"}}"#,
+            track_json("one", "First")
+        );
+        let second = format!(
+            r#"{{"items":[{{"track":{}}},{{"track":{{"id":null,"name":"Local","duration_ms":1,"artists":[],"external_urls":{{}}}}}}],"next":null}}"#,
+            track_json("two", "Second")
+        );
+
+        let server = MockServer::start(vec![
+            MockRoute::new("POST", "/api/token", 200, TOKEN),
+            MockRoute::new("GET", "/v1/playlists/list/tracks?limit=100", 200, &first),
+            MockRoute::new("GET", "/v1/playlists/list/tracks?offset=100&limit=100", 200, &second),
+        ]);
+
+        let tracks = api(&server).playlist("list", 10).await.unwrap().tracks;
+
+        let names: Vec<&str> = tracks.iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, vec!["First", "Second"]);
+    }
+
+    #[tokio::test]
+    async fn playlist_item_cap() {
+        let first = format!(
+            r#"{{"items":[{{"track":{}}},{{"track":{}}}],"next":"{{base}}/v1/playlists/list/tracks?offset=100&limit=100"}}"#,
+            track_json("one", "First"),
+            track_json("two", "Second")
+        );
+
+        let server = MockServer::start(vec![
+            MockRoute::new("POST", "/api/token", 200, TOKEN),
+            MockRoute::new("GET", "/v1/playlists/list/tracks?limit=100", 200, &first),
+        ]);
+
+        let playlist = api(&server).playlist("list", 1).await.unwrap();
+
+        let names: Vec<&str> = playlist.tracks.iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, vec!["First"]);
+        assert!(playlist.warning.unwrap().contains("more than 1 songs"));
+        // The next page is not requested once the cap is reached
+        assert_eq!(server.requests(), vec!["POST /api/token", "GET /v1/playlists/list/tracks?limit=100"]);
+    }
+
+    #[tokio::test]
+    async fn api_error() {
+        let server = MockServer::start(vec![
+            MockRoute::new("POST", "/api/token", 200, TOKEN),
+            MockRoute::new("GET", "/v1/tracks/missing", 404, r#"{"error":{"status":404,"message":"Not found"}}"#),
+        ]);
+
+        let error = api(&server).track("missing").await.unwrap_err();
+
+        assert_eq!(error.to_string(), "Spotify error 404: Not found");
+    }
+
+    #[tokio::test]
+    async fn authentication_error() {
+        let server = MockServer::start(vec![
+            MockRoute::new("POST", "/api/token", 400, r#"{"error":"invalid_client"}"#),
+        ]);
+
+        assert!(api(&server).track("abc").await.is_err());
+        assert_eq!(server.requests(), vec!["POST /api/token"]);
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Canned response for a request, `{base}` in the body is replaced with the server url
+#[derive(Debug, Clone)]
+pub struct MockRoute {
+    method: String,
+    /// Path with the query string, matched exactly
+    target: String,
+    status: u16,
+    body: String
+}
+
+impl MockRoute {
+    pub fn new(method: &str, target: &str, status: u16, body: &str) -> Self {
+        Self {
+            method: method.to_owned(),
+            target: target.to_owned(),
+            status,
+            body: body.to_owned()
+        }
+    }
+}
+
+/// HTTP server on a random local port that answers with the routes it was given,
+/// unknown requests get a 404
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<String>>>
+}
+
+impl MockServer {
+    pub fn start(routes: Vec<MockRoute>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind mock server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let server_url = url.clone();
+        let server_requests = requests.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = handle(stream, &routes, &server_url, &server_requests);
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// Requests received so far as `METHOD /path?query`
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn handle(stream: TcpStream, routes: &[MockRoute], url: &str, requests: &Mutex<Vec<String>>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let target = parts.next().unwrap_or_default().to_owned();
+
+    // Skip the headers, reading the body so the client doesn't get a reset
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header == "\r\n" {
+            break
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    requests.lock().unwrap().push(format!("{} {}", method, target));
+
+    let (status, body) = match routes.iter().find(|route| route.method == method && route.target == target) {
+        Some(route) => (route.status, route.body.replace("{base}", url)),
+        None => (404, "{}".to_owned()),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Empty directory for a test, removed first if a previous run left it there
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("potv3-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Executable shell script that stands in for yt-dlp
+pub fn fake_backend(name: &str, script: &str) -> String {
+    let path = temp_dir(name).join("fake-ytdlp");
+    fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.to_string_lossy().into_owned()
+}
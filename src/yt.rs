@@ -1,186 +1,313 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use reqwest::StatusCode;
 use serde::Deserialize;
-use async_recursion::async_recursion;
+use serde::de::DeserializeOwned;
+
+const API_BASE: &str = "https://www.googleapis.com/youtube/v3";
+
+/// Most items youtube gives in a page
+const PAGE_SIZE: usize = 50;
+
+/// Units spent by the clients made with `YoutubeAPI::new`, they all share the key quota
+static QUOTA: Lazy<Arc<QuotaUsage>> = Lazy::new(Default::default);
+
+pub type YoutubeResult<T> = Result<T, YoutubeError>;
+
+/// Units of the key quota spent today on every endpoint by all the clients
+pub fn quota_used_today () -> HashMap<String, u64> {
+    QUOTA.today()
+}
 
 pub struct YoutubeAPI {
-    key: String
+    key: String,
+    api_base: String,
+    quota: Arc<QuotaUsage>
 }
 
 impl YoutubeAPI {
+    /// Client for `YOUTUBE_API_URL`, the youtube servers by default
     pub fn new (key: &str) -> Self {
+        let api_base = std::env::var("YOUTUBE_API_URL").ok().filter(|url| !url.is_empty()).unwrap_or_else(|| API_BASE.to_owned());
+
         Self {
-            key: key.to_owned()
+            quota: QUOTA.clone(),
+            ..Self::with_base_url(key, &api_base)
         }
     }
 
-    pub async fn video (&self, id: &str) -> YoutubeResult {
-        let search_url = format!("https://www.googleapis.com/youtube/v3/videos?key={}&part=snippet&maxResults=1&id={}", &self.key, id);
-        let result = reqwest::get(search_url).await;
+    /// Client with the key in `YOUTUBE_TOKEN`, None when there is no key
+    pub fn from_env () -> Option<Self> {
+        let key = std::env::var("YOUTUBE_TOKEN").ok().filter(|key| !key.trim().is_empty())?;
 
-        match result {
-            Ok(response) => {
-                match response.text().await {
-                    Ok(text) => {
-                        // println!("{}", text);
-                        let playlist_items_result = serde_json::from_str::<YoutubePlaylistItemsResponse>(&text);
-
-                        match playlist_items_result {
-                            Ok(mut result) => {
-                                for item in result.items.iter_mut() {
-                                    item.snippet.resourceId = Some(YoutubeItemID {
-                                        kind: item.kind.to_owned(),
-                                        videoId: item.id.to_owned(),
-                                    })
-                                }
-                                YoutubeResult::Ok(result)
-                            },
-                            Err(_) => {
-                                let error_result = serde_json::from_str::<YoutubeErrorResponse>(&text);
-
-                                match error_result {
-                                    Ok(error) => YoutubeResult::Error(error.error),
-                                    Err(_) => YoutubeResult::UnknownError(text),
-                                }
-                            },
-                        }
-                    },
-                    Err(_) => YoutubeResult::TextExtractionError,
-                }
-            },
-            Err(_) => YoutubeResult::RequestError,
+        Some(Self::new(key.trim()))
+    }
+
+    /// Client that talks to another server instead of the youtube one, with its own quota count
+    pub fn with_base_url (key: &str, api_base: &str) -> Self {
+        Self {
+            key: key.to_owned(),
+            api_base: api_base.trim_end_matches('/').to_owned(),
+            quota: Default::default()
         }
     }
 
-    pub async fn _search (&self, query: &str) -> YoutubeResult {
-        let search_url = format!("https://www.googleapis.com/youtube/v3/search?key={}&part=snippet&maxResults=1&type=video&q={}", &self.key, query);
-        let result = reqwest::get(search_url).await;
+    /// Units spent today on every endpoint
+    pub fn quota_used (&self) -> HashMap<String, u64> {
+        self.quota.today()
+    }
 
-        match result {
-            Ok(response) => {
-                match response.text().await {
-                    Ok(text) => {
-                        let playlist_items_result = serde_json::from_str::<YoutubeSearchResponse>(&text);
-
-                        match playlist_items_result {
-                            Ok(result) => YoutubeResult::Ok(result._to_playlist_response()),
-                            Err(_) => {
-                                let error_result = serde_json::from_str::<YoutubeErrorResponse>(&text);
-
-                                match error_result {
-                                    Ok(error) => YoutubeResult::Error(error.error),
-                                    Err(_) => YoutubeResult::UnknownError(text),
-                                }
-                            },
-                        }
-                    },
-                    Err(_) => YoutubeResult::TextExtractionError,
-                }
-            },
-            Err(_) => YoutubeResult::RequestError,
+    pub async fn video (&self, id: &str) -> YoutubeResult<YoutubePlaylistItemsResponse> {
+        let mut result = self.get::<YoutubePlaylistItemsResponse>("videos", &[("part", "snippet"), ("maxResults", "1"), ("id", id)]).await?;
+
+        for item in result.items.iter_mut() {
+            item.snippet.resourceId = Some(YoutubeItemID {
+                kind: item.kind.to_owned(),
+                videoId: item.id.to_owned(),
+            })
         }
+
+        Ok(result)
     }
 
-    pub async fn playlist (&self, playlist: &str) -> YoutubeResult {
-        let search_url = format!("https://www.googleapis.com/youtube/v3/playlistItems?key={}&part=snippet&maxResults=50&playlistId={}", &self.key, playlist);
-        let result = reqwest::get(search_url).await;
+    /// The first `max_results` videos found for the query, youtube gives 50 at most
+    pub async fn search (&self, query: &str, max_results: u32) -> YoutubeResult<YoutubePlaylistItemsResponse> {
+        let max_results = max_results.min(PAGE_SIZE as u32).to_string();
 
-        match result {
-            Ok(response) => {
-                match response.text().await {
-                    Ok(text) => {
-                        let playlist_items_result = serde_json::from_str::<YoutubePlaylistItemsResponse>(&text);
-
-                        match playlist_items_result {
-                            Ok(mut result) => {
-                                if let Some(next_page_token) = &result.nextPageToken {
-                                    result.items.append(&mut self.playlist_get_items (playlist, Some(next_page_token)).await);
-                                    YoutubeResult::Ok(result)
-                                } else {
-                                    YoutubeResult::Ok(result)
-                                }
-                            },
-                            Err(_) => {
-                                let error_result = serde_json::from_str::<YoutubeErrorResponse>(&text);
-
-                                match error_result {
-                                    Ok(error) => YoutubeResult::Error(error.error),
-                                    Err(_) => YoutubeResult::UnknownError(text),
-                                }
-                            },
-                        }
-                    },
-                    Err(_) => YoutubeResult::TextExtractionError,
-                }
-            },
-            Err(_) => YoutubeResult::RequestError,
+        self.get::<YoutubeSearchResponse>("search", &[("part", "snippet"), ("maxResults", &max_results), ("type", "video"), ("q", query)])
+            .await
+            .map(|result| result.to_playlist_response())
+    }
+
+    /// Details of the videos, requested 50 at a time. Private and deleted videos are left out by youtube
+    pub async fn videos (&self, ids: &[String]) -> YoutubeResult<HashMap<String, YoutubeVideo>> {
+        let mut videos = HashMap::new();
+        let max_results = PAGE_SIZE.to_string();
+
+        for batch in ids.chunks(PAGE_SIZE) {
+            let ids = batch.join(",");
+            let result = self.get::<YoutubeVideosResponse>("videos", &[("part", "contentDetails,liveStreamingDetails,status"), ("maxResults", &max_results), ("id", &ids)]).await?;
+
+            videos.extend(result.items.into_iter().map(|video| (video.id.to_owned(), video)));
         }
+
+        Ok(videos)
     }
 
-    #[async_recursion]
-    async fn playlist_get_items (&self, playlist: &str, page_token: Option<&'async_recursion str>) -> Vec<YoutubePlaylistItemsResult> {
-        let search_url = if let Some(page_token_str) = &page_token {
-            format!("https://www.googleapis.com/youtube/v3/playlistItems?key={}&part=snippet&maxResults=50&pageToken={}&playlistId={}", &self.key, page_token_str, playlist)
-        } else {
-            format!("https://www.googleapis.com/youtube/v3/playlistItems?key={}&part=snippet&maxResults=50&playlistId={}", &self.key, playlist)
-        };
+    /// Items of the playlist up to `max_items`. When a page fails after the first one
+    /// or the playlist is longer the items loaded until then are returned with a warning
+    pub async fn playlist (&self, playlist: &str, max_items: usize) -> YoutubeResult<YoutubePlaylistItemsResponse> {
+        let mut pages = Box::pin(self.playlist_pages(playlist, max_items));
+        let mut response: Option<YoutubePlaylistItemsResponse> = None;
+
+        while let Some(page) = pages.next().await {
+            response = match (page, response) {
+                (Ok(page), None) => Some(page),
+                (Ok(page), Some(mut response)) => {
+                    response.items.extend(page.items);
+                    response.nextPageToken = page.nextPageToken;
+                    Some(response)
+                },
+                // Nothing was loaded
+                (Err(error), None) => return Err(error),
+                (Err(error), Some(mut response)) => {
+                    response.warning = Some(format!("Only the first {} songs of the playlist were loaded: {}", response.items.len(), error));
+                    Some(response)
+                },
+            };
+        }
 
-        let result = reqwest::get(search_url).await;
+        let mut response = response.ok_or_else(|| YoutubeError::NotFound("The playlist has no pages".to_owned()))?;
 
-        match result {
-            Ok(response) => {
-                match response.text().await {
-                    Ok(text) => {
-                        let playlist_items_result = serde_json::from_str::<YoutubePlaylistItemsResponse>(&text);
-
-                        match playlist_items_result {
-                            Ok(mut result) => {
-                                if let Some(next_page_token) = result.nextPageToken {
-                                    result.items.append(&mut self.playlist_get_items (playlist, Some(&next_page_token)).await);
-                                    result.items
-                                } else {
-                                    result.items
-                                }
-                            },
-                            Err(_) => {
-                                let error_result = serde_json::from_str::<YoutubeErrorResponse>(&text);
-
-                                match error_result {
-                                    Ok(_) => Vec::new(),
-                                    Err(_) => Vec::new(),
-                                }
-                            },
-                        }
-                    },
-                    Err(_) => Vec::new(),
-                }
-            },
-            Err(_) => Vec::new(),
+        // There are more pages but the cap was reached
+        if response.warning.is_none() && response.nextPageToken.is_some() {
+            response.warning = Some(format!("The playlist has more than {} songs, only the first {} were loaded", max_items, response.items.len()));
+        }
+
+        Ok(response)
+    }
+
+    /// Pages of the playlist requested one after the other, it ends after `max_items` items or the first error
+    pub fn playlist_pages<'a> (&'a self, playlist: &'a str, max_items: usize) -> impl Stream<Item = YoutubeResult<YoutubePlaylistItemsResponse>> + 'a {
+        // The token of the next page, None when there are no more pages, and the items loaded so far
+        let start: (Option<Option<String>>, usize) = (Some(None), 0);
+
+        futures::stream::unfold(start, move |(page_token, loaded)| async move {
+            let page_token = page_token?;
+            if loaded >= max_items { return None }
+
+            let left = max_items - loaded;
+            let max_results = left.min(PAGE_SIZE).to_string();
+
+            let mut params = vec![("part", "snippet"), ("maxResults", max_results.as_str()), ("playlistId", playlist)];
+            if let Some(page_token) = &page_token {
+                params.push(("pageToken", page_token.as_str()));
+            }
+
+            match self.get::<YoutubePlaylistItemsResponse>("playlistItems", &params).await {
+                Ok(mut page) => {
+                    page.items.truncate(left);
+                    let loaded = loaded + page.items.len();
+                    let next = page.nextPageToken.clone().map(Some);
+
+                    Some((Ok(page), (next, loaded)))
+                },
+                // The next page is unknown after an error
+                Err(error) => Some((Err(error), (None, loaded))),
+            }
+        })
+    }
+
+    /// Every request goes through here, it spends the quota of the endpoint and turns the error responses into a `YoutubeError`
+    async fn get<T: DeserializeOwned> (&self, endpoint: &str, params: &[(&str, &str)]) -> YoutubeResult<T> {
+        let mut query = vec![("key", self.key.as_str())];
+        query.extend_from_slice(params);
+
+        let url = url::Url::parse_with_params(&format!("{}/{}", &self.api_base, endpoint), &query)
+            .map_err(|err| YoutubeError::Request(err.to_string()))?;
+
+        self.quota.spend(endpoint);
+
+        let response = reqwest::get(url).await.map_err(|err| YoutubeError::Request(err.to_string()))?;
+        let status = response.status();
+        let text = response.text().await.map_err(|err| YoutubeError::Request(err.to_string()))?;
+
+        if !status.is_success() {
+            let error = classify(status, &text);
+            if let YoutubeError::QuotaExceeded(_) = &error {
+                tracing::warn!("youtube quota exceeded, units spent today: {:?}", self.quota_used());
+            }
+            return Err(error)
         }
+
+        serde_json::from_str::<T>(&text).map_err(|err| YoutubeError::Parse(err.to_string()))
     }
 }
 
-#[derive(Debug)]
-pub enum YoutubeResult {
-    Ok(YoutubePlaylistItemsResponse),
-    Error(YoutubeError),
-    RequestError,
-    TextExtractionError,
-    UnknownError(String)
+/// Units of the youtube quota spent on every endpoint, counted again every day
+#[derive(Debug, Default)]
+pub struct QuotaUsage {
+    /// Day number and the units spent on every endpoint that day
+    used: Mutex<(u64, HashMap<String, u64>)>
+}
+
+impl QuotaUsage {
+    fn spend (&self, endpoint: &str) {
+        let mut used = self.used.lock().unwrap();
+        let today = quota_day();
+
+        if used.0 != today {
+            *used = (today, HashMap::new());
+        }
+
+        *used.1.entry(endpoint.to_owned()).or_insert(0) += endpoint_cost(endpoint);
+    }
+
+    fn today (&self) -> HashMap<String, u64> {
+        let used = self.used.lock().unwrap();
+        if used.0 == quota_day() { used.1.clone() } else { HashMap::new() }
+    }
+}
+
+/// Units a request to the endpoint costs, searches are the expensive ones
+fn endpoint_cost (endpoint: &str) -> u64 {
+    match endpoint {
+        "search" => 100,
+        _ => 1,
+    }
+}
+
+/// Days since the epoch in pacific time, when youtube resets the quota. Daylight saving is ignored
+fn quota_day () -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0);
+    now.saturating_sub(8 * 3600) / 86400
 }
 
 // Error
 
+/// Why a request to youtube failed, the messages are shown to users
+#[derive(Debug)]
+pub enum YoutubeError {
+    /// The daily quota of the key is spent
+    QuotaExceeded(String),
+    /// The key is wrong, expired or the api is not enabled for it
+    KeyInvalid(String),
+    NotFound(String),
+    Forbidden(String),
+    /// Any other error youtube answered with
+    Api { code: u16, message: String },
+    /// The request didn't reach youtube or the response was cut
+    Request(String),
+    /// The response is not what we expected
+    Parse(String)
+}
+
+impl fmt::Display for YoutubeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            YoutubeError::QuotaExceeded(_) => write!(f, "The youtube quota for today is spent"),
+            YoutubeError::KeyInvalid(_) => write!(f, "The youtube api key is not valid"),
+            YoutubeError::NotFound(message) => write!(f, "Not found on youtube: {}", message),
+            YoutubeError::Forbidden(message) => write!(f, "Youtube refused the request: {}", message),
+            YoutubeError::Api { code, message } => write!(f, "Youtube error {}: {}", code, message),
+            YoutubeError::Request(message) => write!(f, "The request to youtube failed: {}", message),
+            YoutubeError::Parse(message) => write!(f, "Unexpected youtube response: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for YoutubeError {}
+
+impl YoutubeError {
+    /// The key can't be used right now, the other requests with it would fail too
+    pub fn is_key_unusable (&self) -> bool {
+        matches!(self, YoutubeError::QuotaExceeded(_) | YoutubeError::KeyInvalid(_) | YoutubeError::Forbidden(_))
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct YoutubeErrorResponse {
-    error: YoutubeError
+    error: YoutubeErrorBody
+}
+
+#[derive(Deserialize, Debug)]
+struct YoutubeErrorBody {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    errors: Vec<YoutubeErrorReason>
 }
 
-#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
-pub struct YoutubeError {
-    code: i64,
-    message: String
+struct YoutubeErrorReason {
+    #[serde(default)]
+    reason: String
+}
+
+/// Turn an error response into a `YoutubeError` using the reasons youtube gives
+fn classify (status: StatusCode, text: &str) -> YoutubeError {
+    let (message, reasons) = match serde_json::from_str::<YoutubeErrorResponse>(text) {
+        Ok(response) => (response.error.message, response.error.errors.into_iter().map(|error| error.reason).collect()),
+        Err(_) => (status.to_string(), Vec::new()),
+    };
+    let has_reason = |names: &[&str]| reasons.iter().any(|reason| names.contains(&reason.as_str()));
+
+    if has_reason(&["quotaExceeded", "dailyLimitExceeded", "rateLimitExceeded", "userRateLimitExceeded"]) {
+        YoutubeError::QuotaExceeded(message)
+    } else if has_reason(&["keyInvalid", "keyExpired", "accessNotConfigured"]) || message.contains("API key not valid") {
+        YoutubeError::KeyInvalid(message)
+    } else if status == StatusCode::NOT_FOUND || reasons.iter().any(|reason| reason.ends_with("NotFound")) {
+        YoutubeError::NotFound(message)
+    } else if status == StatusCode::FORBIDDEN || status == StatusCode::UNAUTHORIZED {
+        YoutubeError::Forbidden(message)
+    } else {
+        YoutubeError::Api { code: status.as_u16(), message }
+    }
 }
 
 // Search
@@ -192,7 +319,7 @@ pub struct YoutubeSearchResponse {
 }
 
 impl YoutubeSearchResponse {
-    pub fn _to_playlist_response (&self) -> YoutubePlaylistItemsResponse {
+    pub fn to_playlist_response (&self) -> YoutubePlaylistItemsResponse {
         YoutubePlaylistItemsResponse {
             kind: self.kind.to_owned(),
             items: self.items.iter().map(|item| YoutubePlaylistItemsResult {
@@ -204,10 +331,12 @@ impl YoutubeSearchResponse {
                         kind: item.id.kind.to_owned(),
                         videoId: item.id.videoId.to_owned()
                     }),
-                    thumbnails: item.snippet.thumbnails.clone()
+                    thumbnails: item.snippet.thumbnails.clone(),
+                    channelTitle: item.snippet.channelTitle.clone()
                 },
             }).collect(),
             nextPageToken: None,
+            warning: None
         }
     }
 }
@@ -231,7 +360,9 @@ pub struct YoutubeItemID {
 pub struct YoutubeItemSnippet {
     pub title: String,
     pub resourceId: Option<YoutubeItemID>,
-    pub thumbnails: HashMap<String, YoutubeItemThumbnail>
+    pub thumbnails: HashMap<String, YoutubeItemThumbnail>,
+    #[serde(default)]
+    pub channelTitle: Option<String>
 }
 
 #[allow(non_snake_case)]
@@ -249,7 +380,10 @@ pub struct YoutubeItemThumbnail {
 pub struct YoutubePlaylistItemsResponse {
     pub kind: String,
     pub items: Vec<YoutubePlaylistItemsResult>,
-    pub nextPageToken: Option<String>
+    pub nextPageToken: Option<String>,
+    /// Set when only part of a playlist could be loaded
+    #[serde(skip)]
+    pub warning: Option<String>
 }
 
 #[derive(Deserialize, Debug)]
@@ -257,4 +391,311 @@ pub struct YoutubePlaylistItemsResult {
     pub kind: String,
     pub id: String,
     pub snippet: YoutubeItemSnippet
-}
\ No newline at end of file
+}
+// Videos
+
+#[derive(Deserialize, Debug)]
+pub struct YoutubeVideosResponse {
+    pub items: Vec<YoutubeVideo>
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+pub struct YoutubeVideo {
+    pub id: String,
+    pub contentDetails: Option<YoutubeContentDetails>,
+    pub liveStreamingDetails: Option<YoutubeLiveStreamingDetails>,
+    pub status: Option<YoutubeVideoStatus>
+}
+
+impl YoutubeVideo {
+    /// Seconds, None for streams that are on air
+    pub fn duration (&self) -> Option<f32> {
+        if self.is_live() { return None }
+
+        self.contentDetails.as_ref()?.duration.as_deref().and_then(parse_duration)
+    }
+
+    pub fn is_live (&self) -> bool {
+        self.liveStreamingDetails.as_ref()
+            .map(|live| live.actualStartTime.is_some() && live.actualEndTime.is_none())
+            .unwrap_or(false)
+    }
+
+    pub fn was_live (&self) -> bool {
+        self.liveStreamingDetails.as_ref()
+            .map(|live| live.actualEndTime.is_some())
+            .unwrap_or(false)
+    }
+
+    /// The upload finished and the video is not blocked in the region, every region is fine without one
+    pub fn is_playable_in (&self, region: Option<&str>) -> bool {
+        let uploaded = self.status.as_ref()
+            .and_then(|status| status.uploadStatus.as_deref())
+            .map(|upload_status| matches!(upload_status, "processed" | "uploaded"))
+            .unwrap_or(true);
+
+        let restriction = self.contentDetails.as_ref().and_then(|details| details.regionRestriction.as_ref());
+        let allowed = match (region, restriction) {
+            (Some(region), Some(restriction)) => restriction.allows(region),
+            _ => true,
+        };
+
+        uploaded && allowed
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+pub struct YoutubeContentDetails {
+    /// ISO 8601 duration like `PT4M13S`
+    pub duration: Option<String>,
+    pub regionRestriction: Option<YoutubeRegionRestriction>
+}
+
+/// Regions as ISO 3166-1 alpha-2 codes, when `allowed` is there the video is blocked everywhere else
+#[derive(Deserialize, Debug)]
+pub struct YoutubeRegionRestriction {
+    pub allowed: Option<Vec<String>>,
+    #[serde(default)]
+    pub blocked: Vec<String>
+}
+
+impl YoutubeRegionRestriction {
+    pub fn allows (&self, region: &str) -> bool {
+        let listed = |regions: &[String]| regions.iter().any(|listed| listed.eq_ignore_ascii_case(region));
+
+        match &self.allowed {
+            Some(allowed) => listed(allowed.as_slice()),
+            None => !listed(self.blocked.as_slice()),
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+pub struct YoutubeLiveStreamingDetails {
+    pub actualStartTime: Option<String>,
+    pub actualEndTime: Option<String>
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+pub struct YoutubeVideoStatus {
+    pub uploadStatus: Option<String>
+}
+
+/// Seconds of an ISO 8601 duration like `PT1H2M3S` or `P1DT2H`, None when it can't be parsed
+pub fn parse_duration (duration: &str) -> Option<f32> {
+    let mut rest = duration.strip_prefix('P')?;
+    let mut in_time = false;
+    let mut seconds = 0.0;
+
+    while !rest.is_empty() {
+        if let Some(time) = rest.strip_prefix('T') {
+            in_time = true;
+            rest = time;
+            continue
+        }
+
+        let end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let value: f32 = rest[..end].parse().ok()?;
+
+        let unit = match (rest[end..].chars().next()?, in_time) {
+            ('W', false) => 604800.0,
+            ('D', false) => 86400.0,
+            ('H', true) => 3600.0,
+            ('M', true) => 60.0,
+            ('S', true) => 1.0,
+            _ => return None,
+        };
+
+        seconds += value * unit;
+        rest = &rest[end + 1..];
+    }
+
+    Some(seconds)
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use super::{parse_duration, YoutubeAPI, YoutubeError, YoutubePlaylistItemsResponse, YoutubeResult, YoutubeVideo};
+    use crate::test_utils::{MockRoute, MockServer};
+
+    fn page_json(ids: &[&str], next: Option<&str>) -> String {
+        let items: Vec<String> = ids.iter().map(|id| format!(
+            r#"{{"kind":"youtube#playlistItem","id":"item-{id}","snippet":{{"title":"Song {id}","resourceId":{{"kind":"youtube#video","videoId":"{id}"}},"thumbnails":{{}}}}}}"#
+        )).collect();
+        let next = next.map(|next| format!(r#","nextPageToken":"{}""#, next)).unwrap_or_default();
+
+        format!(r#"{{"kind":"youtube#playlistItemListResponse","items":[{}]{}}}"#, items.join(","), next)
+    }
+
+    const ERROR: &str = r#"{"error":{"code":500,"message":"Backend Error"}}"#;
+
+    fn ids(result: &YoutubeResult<YoutubePlaylistItemsResponse>) -> Vec<String> {
+        match result {
+            Ok(response) => response.items.iter().map(|item| item.snippet.resourceId.as_ref().unwrap().videoId.to_owned()).collect(),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn playlist_follows_pages() {
+        let server = MockServer::start(vec![
+            MockRoute::new("GET", "/playlistItems?key=k&part=snippet&maxResults=50&playlistId=PL", 200, &page_json(&["a", "b"], Some("p2"))),
+            MockRoute::new("GET", "/playlistItems?key=k&part=snippet&maxResults=50&playlistId=PL&pageToken=p2", 200, &page_json(&["c"], None)),
+        ]);
+
+        let result = YoutubeAPI::with_base_url("k", &server.url).playlist("PL", 100).await;
+
+        assert_eq!(ids(&result), vec!["a", "b", "c"]);
+        assert_eq!(server.requests().len(), 2);
+        assert!(result.unwrap().warning.is_none());
+    }
+
+    #[tokio::test]
+    async fn playlist_stops_at_the_cap() {
+        let server = MockServer::start(vec![
+            MockRoute::new("GET", "/playlistItems?key=k&part=snippet&maxResults=3&playlistId=PL", 200, &page_json(&["a", "b"], Some("p2"))),
+            MockRoute::new("GET", "/playlistItems?key=k&part=snippet&maxResults=1&playlistId=PL&pageToken=p2", 200, &page_json(&["c", "d"], Some("p3"))),
+        ]);
+
+        let api = YoutubeAPI::with_base_url("k", &server.url);
+        let pages: Vec<YoutubeResult<YoutubePlaylistItemsResponse>> = api.playlist_pages("PL", 3).collect().await;
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(ids(&pages[1]), vec!["c"]);
+        // The third page is never requested
+        assert_eq!(server.requests().len(), 2);
+
+        let result = api.playlist("PL", 3).await;
+        assert_eq!(ids(&result), vec!["a", "b", "c"]);
+        assert!(result.unwrap().warning.unwrap().contains("more than 3 songs"));
+    }
+
+    #[tokio::test]
+    async fn playlist_keeps_the_pages_before_an_error() {
+        let server = MockServer::start(vec![
+            MockRoute::new("GET", "/playlistItems?key=k&part=snippet&maxResults=50&playlistId=PL", 200, &page_json(&["a", "b"], Some("p2"))),
+            MockRoute::new("GET", "/playlistItems?key=k&part=snippet&maxResults=50&playlistId=PL&pageToken=p2", 500, ERROR),
+        ]);
+
+        let result = YoutubeAPI::with_base_url("k", &server.url).playlist("PL", 100).await;
+
+        assert_eq!(ids(&result), vec!["a", "b"]);
+        match result {
+            Ok(response) => assert!(response.warning.unwrap().contains("Backend Error")),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn playlist_error_on_the_first_page() {
+        let server = MockServer::start(vec![
+            MockRoute::new("GET", "/playlistItems?key=k&part=snippet&maxResults=50&playlistId=PL", 500, ERROR),
+        ]);
+
+        let result = YoutubeAPI::with_base_url("k", &server.url).playlist("PL", 100).await;
+
+        assert!(matches!(result, Err(YoutubeError::Api { code: 500, .. })));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT4M13S"), Some(253.0));
+        assert_eq!(parse_duration("PT1H"), Some(3600.0));
+        assert_eq!(parse_duration("P1DT2H3M"), Some(93780.0));
+        assert_eq!(parse_duration("PT0S"), Some(0.0));
+        assert_eq!(parse_duration("PT1.5S"), Some(1.5));
+        assert_eq!(parse_duration("P0D"), Some(0.0));
+        assert_eq!(parse_duration("4M13S"), None);
+        assert_eq!(parse_duration("PT4X"), None);
+        assert_eq!(parse_duration("PTM"), None);
+    }
+
+    #[test]
+    fn video_details() {
+        let video = |json: &str| serde_json::from_str::<YoutubeVideo>(json).unwrap();
+
+        let normal = video(r#"{"id":"a","contentDetails":{"duration":"PT3M"},"status":{"uploadStatus":"processed"}}"#);
+        assert_eq!(normal.duration(), Some(180.0));
+        assert!(!normal.is_live() && !normal.was_live());
+        assert!(normal.is_playable_in(Some("MX")));
+
+        let live = video(r#"{"id":"b","contentDetails":{"duration":"P0D"},"liveStreamingDetails":{"actualStartTime":"2023-01-01T00:00:00Z"}}"#);
+        assert_eq!(live.duration(), None);
+        assert!(live.is_live());
+
+        let ended = video(r#"{"id":"c","contentDetails":{"duration":"PT1H"},"liveStreamingDetails":{"actualStartTime":"2023-01-01T00:00:00Z","actualEndTime":"2023-01-01T01:00:00Z"}}"#);
+        assert_eq!(ended.duration(), Some(3600.0));
+        assert!(ended.was_live() && !ended.is_live());
+
+        let blocked = video(r#"{"id":"d","contentDetails":{"duration":"PT1M","regionRestriction":{"blocked":["MX","US"]}}}"#);
+        assert!(!blocked.is_playable_in(Some("mx")));
+        assert!(blocked.is_playable_in(Some("DE")));
+        assert!(blocked.is_playable_in(None));
+
+        let allowed = video(r#"{"id":"e","contentDetails":{"duration":"PT1M","regionRestriction":{"allowed":["JP"]}}}"#);
+        assert!(allowed.is_playable_in(Some("JP")));
+        assert!(!allowed.is_playable_in(Some("MX")));
+
+        let rejected = video(r#"{"id":"f","status":{"uploadStatus":"rejected"}}"#);
+        assert!(!rejected.is_playable_in(None));
+    }
+
+    #[tokio::test]
+    async fn videos_leave_out_missing_ones() {
+        let server = MockServer::start(vec![
+            MockRoute::new("GET", "/videos?key=k&part=contentDetails%2CliveStreamingDetails%2Cstatus&maxResults=50&id=a%2Cgone", 200, r#"{"items":[{"id":"a","contentDetails":{"duration":"PT10S"}}]}"#),
+        ]);
+
+        let videos = YoutubeAPI::with_base_url("k", &server.url).videos(&["a".to_owned(), "gone".to_owned()]).await.unwrap();
+
+        assert_eq!(videos.len(), 1);
+        assert_eq!(videos["a"].duration(), Some(10.0));
+    }
+
+    fn error_json(code: u16, reason: &str) -> String {
+        format!(r#"{{"error":{{"code":{code},"message":"Message","errors":[{{"domain":"youtube","reason":"{reason}","message":"Message"}}]}}}}"#)
+    }
+
+    #[tokio::test]
+    async fn errors_are_classified() {
+        let server = MockServer::start(vec![
+            MockRoute::new("GET", "/videos?key=quota&part=snippet&maxResults=1&id=a", 403, &error_json(403, "quotaExceeded")),
+            MockRoute::new("GET", "/videos?key=bad&part=snippet&maxResults=1&id=a", 400, &error_json(400, "keyInvalid")),
+            MockRoute::new("GET", "/videos?key=k&part=snippet&maxResults=1&id=gone", 404, &error_json(404, "videoNotFound")),
+            MockRoute::new("GET", "/videos?key=k&part=snippet&maxResults=1&id=secret", 403, &error_json(403, "forbidden")),
+        ]);
+
+        let video = |key: &'static str, id: &'static str| {
+            let url = server.url.clone();
+            async move { YoutubeAPI::with_base_url(key, &url).video(id).await }
+        };
+
+        assert!(matches!(video("quota", "a").await, Err(YoutubeError::QuotaExceeded(_))));
+        assert!(matches!(video("bad", "a").await, Err(YoutubeError::KeyInvalid(_))));
+        assert!(matches!(video("k", "gone").await, Err(YoutubeError::NotFound(_))));
+        assert!(matches!(video("k", "secret").await, Err(YoutubeError::Forbidden(_))));
+    }
+
+    #[tokio::test]
+    async fn quota_is_counted_per_endpoint() {
+        let server = MockServer::start(vec![
+            MockRoute::new("GET", "/search?key=k&part=snippet&maxResults=5&type=video&q=some+song", 200, r#"{"kind":"youtube#searchListResponse","items":[]}"#),
+            MockRoute::new("GET", "/playlistItems?key=k&part=snippet&maxResults=50&playlistId=PL", 200, &page_json(&["a"], None)),
+        ]);
+
+        let api = YoutubeAPI::with_base_url("k", &server.url);
+        api.search("some song", 5).await.unwrap();
+        api.playlist("PL", 100).await.unwrap();
+        api.playlist("PL", 100).await.unwrap();
+
+        let used = api.quota_used();
+        assert_eq!(used.get("search"), Some(&100));
+        assert_eq!(used.get("playlistItems"), Some(&2));
+    }
+}
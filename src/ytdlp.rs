@@ -0,0 +1,269 @@
+use std::fmt;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Size of the chunks read from the yt-dlp stdout
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// A stream is given up when yt-dlp doesn't send anything for this long
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why yt-dlp couldn't give us what we asked for, the messages are shown to users
+#[derive(Debug)]
+pub enum MediaError {
+    /// yt-dlp or youtube-dl is not installed
+    BinaryMissing(String),
+    /// The extractor failed, with the error printed by yt-dlp
+    Extractor(String),
+    /// yt-dlp took longer than the timeout
+    Timeout(Duration),
+    /// The media is not available in the country of the bot
+    GeoBlocked,
+    /// A skip, leave or shutdown made the work useless
+    Cancelled,
+    Io(std::io::Error)
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaError::BinaryMissing(binary) => write!(f, "{} is not installed on the bot host", binary),
+            MediaError::Extractor(message) => write!(f, "{}", message),
+            MediaError::Timeout(timeout) => write!(f, "Gave up after waiting {} seconds for the media", timeout.as_secs()),
+            MediaError::GeoBlocked => write!(f, "This media is not available in the country of the bot"),
+            MediaError::Cancelled => write!(f, "Cancelled"),
+            MediaError::Io(err) => write!(f, "Cannot run yt-dlp: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MediaError {}
+
+/// True when the error comes from a cancelled yt-dlp, those are not worth reporting
+pub fn is_cancelled(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<MediaError>(), Some(MediaError::Cancelled))
+}
+
+/// Per guild signal that stops the yt-dlp processes started for the guild
+#[derive(Debug)]
+pub struct Canceller(watch::Sender<u64>);
+
+impl Default for Canceller {
+    fn default() -> Self {
+        Self(watch::channel(0).0)
+    }
+}
+
+impl Canceller {
+    /// Token that fires on the next `cancel`, earlier cancellations don't affect it
+    pub fn token(&self) -> CancelToken {
+        CancelToken(self.0.subscribe())
+    }
+
+    pub fn cancel(&self) {
+        self.0.send_modify(|generation| *generation += 1);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CancelToken(watch::Receiver<u64>);
+
+impl CancelToken {
+    /// Token for work that no skip or leave can make useless
+    pub fn never() -> Self {
+        Self(watch::channel(0).1)
+    }
+
+    /// Completes when the work has to stop
+    pub async fn cancelled(&mut self) {
+        if self.0.changed().await.is_err() {
+            // The canceller is gone so it will never fire
+            std::future::pending::<()>().await
+        }
+    }
+}
+
+/// Time limit of a yt-dlp invocation in seconds from `YTDLP_TIMEOUT`, 120 by default
+pub fn timeout() -> Duration {
+    let secs = std::env::var("YTDLP_TIMEOUT").ok().and_then(|value| value.parse::<u64>().ok()).unwrap_or(120);
+    Duration::from_secs(secs)
+}
+
+/// Run the future until it completes, the timeout expires or the token is cancelled
+async fn guarded<T>(future: impl Future<Output = T>, limit: Duration, cancel: &mut CancelToken) -> Result<T, MediaError> {
+    tokio::select! {
+        result = tokio::time::timeout(limit, future) => result.map_err(|_| MediaError::Timeout(limit)),
+        _ = cancel.cancelled() => Err(MediaError::Cancelled),
+    }
+}
+
+fn spawn(binary: &str, args: &[&str]) -> Result<Child, MediaError> {
+    Command::new(binary)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Dropping the child after a timeout or a cancellation kills it
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| match err.kind() {
+            ErrorKind::NotFound => MediaError::BinaryMissing(binary.to_owned()),
+            _ => MediaError::Io(err),
+        })
+}
+
+/// Turn what yt-dlp printed to stderr when it failed into an error
+fn classify(stderr: &str) -> MediaError {
+    // The json output goes to stderr too and descriptions can say anything
+    let messages: Vec<&str> = stderr.lines().filter(|line| !line.starts_with('{')).collect();
+    let lowercase = messages.join("\n").to_lowercase();
+
+    if lowercase.contains("available in your country") || lowercase.contains("geo restrict") || lowercase.contains("geo-restrict") {
+        return MediaError::GeoBlocked
+    }
+
+    let message = messages
+        .iter()
+        .rev()
+        .find_map(|line| line.strip_prefix("ERROR:"))
+        .map(|message| message.trim().to_owned())
+        .unwrap_or_else(|| "yt-dlp failed without saying why".to_owned());
+
+    MediaError::Extractor(message)
+}
+
+/// Run yt-dlp until it exits and returns the json lines it printed
+pub async fn json_lines(binary: &str, args: &[&str], cancel: &mut CancelToken) -> Result<Vec<String>, MediaError> {
+    let child = spawn(binary, args)?;
+    let output = guarded(child.wait_with_output(), timeout(), cancel).await?.map_err(MediaError::Io)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // yt-dlp prints the json to stderr when `-o -` is used
+    let lines: Vec<String> = stdout
+        .lines()
+        .chain(stderr.lines())
+        .filter(|line| line.starts_with('{'))
+        .map(|line| line.to_owned())
+        .collect();
+
+    if lines.is_empty() && !output.status.success() {
+        return Err(classify(&stderr))
+    }
+
+    Ok(lines)
+}
+
+/// yt-dlp writing the media to stdout
+pub struct MediaStream {
+    pub child: Child,
+    pub stdout: ChildStdout,
+    stderr: JoinHandle<String>
+}
+
+impl MediaStream {
+    pub fn spawn(binary: &str, args: &[&str]) -> Result<Self, MediaError> {
+        let mut child = spawn(binary, args)?;
+
+        let stdout = child.stdout.take().ok_or_else(|| MediaError::Io(ErrorKind::BrokenPipe.into()))?;
+        let mut stderr = child.stderr.take().ok_or_else(|| MediaError::Io(ErrorKind::BrokenPipe.into()))?;
+
+        // Always read stderr so yt-dlp never blocks writing to it
+        let stderr = tokio::spawn(async move {
+            let mut output = String::new();
+            let _ = stderr.read_to_string(&mut output).await;
+            output
+        });
+
+        Ok(Self { child, stdout, stderr })
+    }
+
+    /// Wait for the first bytes of the media, when yt-dlp exits without writing anything its error is returned
+    pub async fn first_chunk(&mut self, cancel: &mut CancelToken) -> Result<Vec<u8>, MediaError> {
+        self.first_chunk_within(timeout(), cancel).await
+    }
+
+    async fn first_chunk_within(&mut self, limit: Duration, cancel: &mut CancelToken) -> Result<Vec<u8>, MediaError> {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let read = guarded(self.stdout.read(&mut chunk), limit, cancel).await?.map_err(MediaError::Io)?;
+
+        if read == 0 {
+            let stderr = guarded(&mut self.stderr, STALL_TIMEOUT, cancel).await?.unwrap_or_default();
+            return Err(classify(&stderr))
+        }
+
+        chunk.truncate(read);
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{is_cancelled, json_lines, Canceller, CancelToken, MediaError, MediaStream};
+    use crate::test_utils::fake_backend;
+
+    #[tokio::test]
+    async fn json_lines_from_stdout_and_stderr() {
+        let binary = fake_backend("ytdlp-json", r#"echo '{"id":"one"}'; echo 'WARNING: slow' >&2; echo '{"id":"two"}' >&2"#);
+
+        let lines = json_lines(&binary, &[], &mut CancelToken::never()).await.unwrap();
+
+        assert_eq!(lines, vec![r#"{"id":"one"}"#, r#"{"id":"two"}"#]);
+    }
+
+    #[tokio::test]
+    async fn stream_error_before_media() {
+        let binary = fake_backend("ytdlp-stream-error", "echo 'ERROR: [generic] Unsupported URL' >&2; exit 1");
+
+        let mut stream = MediaStream::spawn(&binary, &[]).unwrap();
+        let error = stream.first_chunk(&mut CancelToken::never()).await.unwrap_err();
+
+        assert!(matches!(error, MediaError::Extractor(message) if message == "[generic] Unsupported URL"));
+    }
+
+    #[tokio::test]
+    async fn stream_first_chunk() {
+        let binary = fake_backend("ytdlp-stream", "printf 'media'");
+
+        let mut stream = MediaStream::spawn(&binary, &[]).unwrap();
+
+        assert_eq!(stream.first_chunk(&mut CancelToken::never()).await.unwrap(), b"media");
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_times_out() {
+        let binary = fake_backend("ytdlp-stall", "exec sleep 10");
+
+        let mut stream = MediaStream::spawn(&binary, &[]).unwrap();
+        let error = stream.first_chunk_within(Duration::from_millis(200), &mut CancelToken::never()).await.unwrap_err();
+
+        assert!(matches!(error, MediaError::Timeout(limit) if limit == Duration::from_millis(200)));
+    }
+
+    #[tokio::test]
+    async fn cancelled() {
+        let binary = fake_backend("ytdlp-cancel", "exec sleep 10");
+
+        let canceller = Canceller::default();
+        let mut token = canceller.token();
+
+        let cancel = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        };
+        let (result, _) = tokio::join!(json_lines(&binary, &[], &mut token), cancel);
+
+        let error = anyhow::Error::from(result.unwrap_err());
+        assert!(is_cancelled(&error));
+    }
+}